@@ -0,0 +1,240 @@
+use std::fmt::Write;
+
+use error::{Error, Location, Note};
+use lexer;
+use loader::FileLoader;
+use processor::Warning;
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const MAGENTA: &str = "\x1b[1;35m";
+const CYAN: &str = "\x1b[1;36m";
+const GREEN: &str = "\x1b[1;32m";
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// How diagnostics are rendered: plain text for logs, or with ANSI colors for terminals.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Style {
+    Plain,
+    Colored,
+}
+
+impl Style {
+    fn paint(self, color: &str, text: &str) -> String {
+        match self {
+            Style::Plain => text.to_string(),
+            Style::Colored => format!("{}{}{}", color, text, RESET),
+        }
+    }
+}
+
+/// An error or a warning prepared for rendering.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub location: Option<Location>,
+    /// The `#include` directives which led to the file, innermost first.
+    pub include_stack: Vec<Location>,
+    pub notes: Vec<Note>,
+}
+
+impl From<&Error> for Diagnostic {
+    fn from(err: &Error) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            message: err.kind.to_string(),
+            location: err.context.location.clone(),
+            include_stack: err.context.include_stack.clone(),
+            notes: err.context.notes.clone(),
+        }
+    }
+}
+
+impl From<&Warning> for Diagnostic {
+    fn from(warning: &Warning) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            message: warning.message.clone(),
+            location: Some(warning.location.clone()),
+            include_stack: vec![],
+            notes: vec![],
+        }
+    }
+}
+
+impl Diagnostic {
+    /// Renders the diagnostic like GCC does, with the include chain, the source line with the
+    /// token at the location underlined, and the notes. `sources` gives the code of the files by
+    /// the names in the locations, including the processed code itself as `<input>` or by the
+    /// name given to `Processor::process_file`. The source line is left out if the file can't be
+    /// loaded.
+    pub fn render<L>(&self, sources: &L, style: Style) -> String
+    where
+        L: FileLoader,
+    {
+        let mut out = String::new();
+        for (i, location) in self.include_stack.iter().enumerate() {
+            let prefix = if i == 0 { "in file included from" } else { "                 from" };
+            let end = if i + 1 == self.include_stack.len() { ':' } else { ',' };
+            let location = format!("{}:{}", location.file, location.line);
+            let _ = writeln!(out, "{} {}{}", prefix, style.paint(BOLD, &location), end);
+        }
+        let (label, color) = match self.severity {
+            Severity::Error => ("error:", RED),
+            Severity::Warning => ("warning:", MAGENTA),
+        };
+        let location = self.location.as_ref();
+        render_message(&mut out, label, color, &self.message, location, sources, style);
+        for note in &self.notes {
+            let location = Some(&note.location);
+            render_message(&mut out, "note:", CYAN, &note.message, location, sources, style);
+        }
+        out
+    }
+}
+
+fn render_message<L>(
+    out: &mut String,
+    label: &str,
+    color: &str,
+    message: &str,
+    location: Option<&Location>,
+    sources: &L,
+    style: Style,
+) where
+    L: FileLoader,
+{
+    let location = match location {
+        Some(location) => location,
+        None => {
+            let _ = writeln!(out, "{} {}", style.paint(color, label), message);
+            return;
+        }
+    };
+    let _ = writeln!(
+        out,
+        "{} {} {}",
+        style.paint(BOLD, &format!("{}:", location)),
+        style.paint(color, label),
+        message
+    );
+    let code = match sources.load(location.file.as_str()) {
+        Some(code) => code,
+        None => return,
+    };
+    let line = match code.lines().nth(location.line.wrapping_sub(1)) {
+        Some(line) => line.trim_end_matches('\r'),
+        None => return,
+    };
+    let number = location.line.to_string();
+    let gutter = number.len().max(5);
+    let _ = writeln!(out, "{:>width$} | {}", number, line, width = gutter);
+
+    // The underline keeps the tabs of the line, so that it stays aligned with the code.
+    let column = location.column.max(1) - 1;
+    let indent: String = line
+        .chars()
+        .take(column)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let rest = line.char_indices().nth(column).map_or("", |(i, _)| &line[i..]);
+    let underline = format!("^{}", "~".repeat(token_width(rest).saturating_sub(1)));
+    let _ = writeln!(out, "{:>width$} | {}{}", "", indent, style.paint(GREEN, &underline),
+                     width = gutter);
+}
+
+/// The width in characters of the token `code` starts with, at least one. A macro invocation
+/// counts up to its closing parenthesis, when that's on the same line.
+fn token_width(code: &str) -> usize {
+    let tokens = match lexer::tokenize_spanned(code) {
+        Ok(tokens) => tokens,
+        Err(_) => return 1,
+    };
+    let end = match tokens.get(..2) {
+        Some([(lexer::Token::Whitespace, _), _]) => return 1,
+        Some([(lexer::Token::Word(_), _), (_, span)]) => {
+            invocation_end(&tokens[1..]).unwrap_or(span.offset)
+        }
+        Some([_, (_, span)]) => span.offset,
+        _ => code.len(),
+    };
+    code[..end].chars().count().max(1)
+}
+
+/// The offset after the closing parenthesis, if the tokens are the arguments of an invocation.
+fn invocation_end(tokens: &[(lexer::Token, lexer::Span)]) -> Option<usize> {
+    let mut tokens = tokens.iter().filter(|(token, _)| *token != lexer::Token::Whitespace);
+    if tokens.next()?.0 != lexer::Token::Char('(') {
+        return None;
+    }
+    let mut depth = 0;
+    for (token, span) in tokens {
+        match *token {
+            lexer::Token::Char('(') => depth += 1,
+            lexer::Token::Char(')') if depth == 0 => return Some(span.offset + 1),
+            lexer::Token::Char(')') => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+#[test]
+fn test_render() {
+    let code = "\
+#define LIGHTS 8
+#include \"lights.glsl\"
+";
+    let lights = "\
+#define SHADE(a, b) a * b
+vec3 c = SHADE(color);
+";
+    let sources = |name: &str| match name {
+        "<input>" => Some(code.to_string()),
+        "lights.glsl" => Some(lights.to_string()),
+        _ => None,
+    };
+    let err = ::processor::process(code, &[], sources).unwrap_err();
+    assert_eq!(
+        Diagnostic::from(&err).render(&sources, Style::Plain),
+        "\
+in file included from <input>:2:
+lights.glsl:2:10: error: wrong number of arguments given to macro `SHADE`
+    2 | vec3 c = SHADE(color);
+      |          ^~~~~~~~~~~~
+lights.glsl:1:1: note: macro defined here
+    1 | #define SHADE(a, b) a * b
+      | ^~~~~~~
+"
+    );
+}
+
+#[test]
+fn test_render_warning() {
+    let code = "foo\n\t#warning Deprecated\n";
+    let output = ::processor::process(code, &[], |_| None).unwrap();
+    let sources = |_: &str| Some(code.to_string());
+    assert_eq!(
+        Diagnostic::from(&output.warnings[0]).render(&sources, Style::Plain),
+        "<input>:2:2: warning: Deprecated\n    2 | \t#warning Deprecated\n      | \t^~~~~~~~\n"
+    );
+    assert_eq!(
+        Diagnostic::from(&output.warnings[0]).render(&|_: &str| None, Style::Colored),
+        "\x1b[1m<input>:2:2:\x1b[0m \x1b[1;35mwarning:\x1b[0m Deprecated\n"
+    );
+}
+
+#[test]
+fn test_token_width() {
+    assert_eq!(token_width("vec3 c"), 4);
+    assert_eq!(token_width("F (a, (b)) + 1"), 10);
+    assert_eq!(token_width("F(a,"), 1);
+    assert_eq!(token_width("#define F"), 7);
+}
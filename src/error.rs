@@ -1,10 +1,38 @@
+use std::error;
+use std::fmt;
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 
+/// Position in the processed code. The file is the name given to `#include`, and the column
+/// counts characters from 1.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Additional information about an error at another location, like the definition of the macro
+/// whose invocation failed.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Note {
+    pub message: String,
+    pub location: Location,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum LexingError {
+    UnterminatedString,
+    UnterminatedCharacter,
+    UnterminatedComment,
+    StrayBackslash,
+    InvalidUtf8,
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum ParseError {
     UnknownPreprocessorDirective(String),
-    MissingParameter,
+    MissingParameter(String),
     MissingNewline,
     ExpectedWhitespace,
     UnknownError,
@@ -12,20 +40,317 @@ pub enum ParseError {
     UnrecognizedPreprocessor(String),
     UnexpectedPreprocessor(String),
     ElseWithoutEndif,
+    ElifAfterElse(String),
     IfWithoutEndif,
+    InvalidMacroParameters,
+    StringizeWithoutParameter,
+    MisplacedTokenPaste,
+    UnexpectedVariadicIdentifier,
+    InvalidVaOpt,
+    InvalidLineDirective,
+    InvalidPragmaArguments(String),
+    InvalidHeaderName,
+    InvalidEmbedParameter(String),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ExpressionError {
+    DivisionByZero,
+    InvalidLiteral(String),
+    UnexpectedToken(String),
+    UnexpectedEnd,
 }
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, PartialEq, Clone)]
-pub enum Error {
-    LexingError,
+pub enum ErrorKind {
+    LexingError(LexingError),
     ParsingError(ParseError),
+    ExpressionError(ExpressionError),
     CantOpenFile,
-    MaxRecursionDepthReached,
+    IncludeTooDeep(usize),
+    UnterminatedMacroInvocation(String),
+    MacroArgumentCountMismatch(String),
+    InvalidTokenPaste(String),
+    ErrorDirective(String),
+    RejectedPragma(String),
     None,
 }
 
+/// An error, and where it happened when that's known.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Error {
+    pub kind: ErrorKind,
+    /// Boxed, so that results carrying an error stay small.
+    pub context: Box<ErrorContext>,
+}
+
+/// Where an error happened, and what led there.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ErrorContext {
+    pub location: Option<Location>,
+    /// The `#include` directives which led to the file of the error, innermost first.
+    pub include_stack: Vec<Location>,
+    pub notes: Vec<Note>,
+}
+
+impl Error {
+    /// Sets the location, unless the error already has one. Errors are located first where
+    /// they're raised, and then by the item of the file they happen in.
+    pub(crate) fn or_at(mut self, location: Location) -> Error {
+        if self.context.location.is_none() {
+            self.context.location = Some(location);
+        }
+        self
+    }
+
+    /// Sets the file of a location given by the lexer or the parser, which don't know the file.
+    pub(crate) fn in_file(mut self, file: &str) -> Error {
+        if let Some(ref mut location) = self.context.location {
+            if location.file.is_empty() {
+                location.file = file.to_string();
+            }
+        }
+        self
+    }
+
+    /// Adds the `#include` of the file the error happened in, as the error propagates out of it.
+    pub(crate) fn included_from(mut self, location: Location) -> Error {
+        self.context.include_stack.push(location);
+        self
+    }
+
+    /// Adds a note, if its location is known.
+    pub(crate) fn with_note(mut self, message: String, location: Option<&Location>) -> Error {
+        if let Some(location) = location {
+            self.context.notes.push(Note {
+                message,
+                location: location.clone(),
+            });
+        }
+        self
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Error {
+        Error {
+            kind,
+            context: Box::default(),
+        }
+    }
+}
+
+impl From<LexingError> for Error {
+    fn from(err: LexingError) -> Error {
+        ErrorKind::LexingError(err).into()
+    }
+}
+
 impl From<ParseError> for Error {
     fn from(err: ParseError) -> Error {
-        Error::ParsingError(err)
+        ErrorKind::ParsingError(err).into()
+    }
+}
+
+impl From<ExpressionError> for Error {
+    fn from(err: ExpressionError) -> Error {
+        ErrorKind::ExpressionError(err).into()
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+impl fmt::Display for LexingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LexingError::UnterminatedString => write!(f, "unterminated string literal"),
+            LexingError::UnterminatedCharacter => write!(f, "unterminated character literal"),
+            LexingError::UnterminatedComment => write!(f, "unterminated block comment"),
+            LexingError::StrayBackslash => write!(f, "stray `\\` not followed by a newline"),
+            LexingError::InvalidUtf8 => write!(f, "invalid UTF-8"),
+        }
+    }
+}
+
+impl error::Error for LexingError {}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::UnknownPreprocessorDirective(ref name) |
+            ParseError::UnrecognizedPreprocessor(ref name) => {
+                write!(f, "unknown preprocessor directive `#{}`", name)
+            }
+            ParseError::MissingParameter(ref name) => {
+                let parameter = match name.as_str() {
+                    "include" | "include_next" | "embed" => "a file name",
+                    "define" | "undef" | "ifdef" | "ifndef" | "elifdef" | "elifndef" => {
+                        "a macro name"
+                    }
+                    "if" | "elif" => "an expression",
+                    "line" => "a line number",
+                    _ => "a parameter",
+                };
+                write!(f, "#{} expects {}", name, parameter)
+            }
+            ParseError::MissingNewline => write!(f, "expected a newline after the directive"),
+            ParseError::ExpectedWhitespace => write!(f, "expected whitespace"),
+            ParseError::UnknownError => write!(f, "unknown parse error"),
+            ParseError::UnspportedPreprocessor(ref name) => {
+                write!(f, "unsupported preprocessor directive `#{}`", name)
+            }
+            ParseError::UnexpectedPreprocessor(ref name) => {
+                write!(f, "`#{}` without a matching `#if`", name)
+            }
+            ParseError::ElseWithoutEndif => write!(f, "`#else` without a matching `#endif`"),
+            ParseError::ElifAfterElse(ref name) => write!(f, "`#{}` after `#else`", name),
+            ParseError::IfWithoutEndif => write!(f, "`#if` without a matching `#endif`"),
+            ParseError::InvalidMacroParameters => write!(f, "invalid macro parameters"),
+            ParseError::StringizeWithoutParameter => {
+                write!(f, "`#` is not followed by a macro parameter")
+            }
+            ParseError::MisplacedTokenPaste => {
+                write!(f, "`##` can't be at either end of a macro definition")
+            }
+            ParseError::UnexpectedVariadicIdentifier => {
+                write!(f, "`__VA_ARGS__` or `__VA_OPT__` outside of a variadic macro")
+            }
+            ParseError::InvalidVaOpt => write!(f, "invalid `__VA_OPT__`"),
+            ParseError::InvalidLineDirective => write!(f, "invalid `#line` directive"),
+            ParseError::InvalidPragmaArguments(ref name) => {
+                write!(f, "invalid arguments of `#pragma {}`", name)
+            }
+            ParseError::InvalidHeaderName => {
+                write!(f, "invalid header name, expected `\"file\"` or `<file>`")
+            }
+            ParseError::InvalidEmbedParameter(ref name) => {
+                write!(f, "invalid `#embed` parameter `{}`", name)
+            }
+        }
+    }
+}
+
+impl error::Error for ParseError {}
+
+impl fmt::Display for ExpressionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExpressionError::DivisionByZero => write!(f, "division by zero"),
+            ExpressionError::InvalidLiteral(ref literal) => {
+                write!(f, "invalid literal `{}`", literal)
+            }
+            ExpressionError::UnexpectedToken(ref token) => {
+                write!(f, "unexpected token `{}` in expression", token)
+            }
+            ExpressionError::UnexpectedEnd => write!(f, "unexpected end of expression"),
+        }
+    }
+}
+
+impl error::Error for ExpressionError {}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorKind::LexingError(ref err) => err.fmt(f),
+            ErrorKind::ParsingError(ref err) => err.fmt(f),
+            ErrorKind::ExpressionError(ref err) => err.fmt(f),
+            ErrorKind::CantOpenFile => write!(f, "can't open an included file"),
+            ErrorKind::IncludeTooDeep(depth) => {
+                write!(f, "#include nested deeper than {} levels", depth)
+            }
+            ErrorKind::UnterminatedMacroInvocation(ref name) => {
+                write!(f, "unterminated invocation of macro `{}`", name)
+            }
+            ErrorKind::MacroArgumentCountMismatch(ref name) => {
+                write!(f, "wrong number of arguments given to macro `{}`", name)
+            }
+            ErrorKind::InvalidTokenPaste(ref pasted) => {
+                write!(f, "pasting gives `{}`, which isn't a valid token", pasted)
+            }
+            ErrorKind::ErrorDirective(ref message) => write!(f, "#error {}", message),
+            ErrorKind::RejectedPragma(ref message) => write!(f, "rejected pragma: {}", message),
+            ErrorKind::None => write!(f, "unknown error"),
+        }
     }
 }
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Like GCC, the include chain is given on its own line before the error.
+        for (i, location) in self.context.include_stack.iter().enumerate() {
+            let prefix = if i == 0 { "in file included" } else { "," };
+            write!(f, "{} from {}:{}", prefix, location.file, location.line)?;
+        }
+        if !self.context.include_stack.is_empty() {
+            writeln!(f, ":")?;
+        }
+        match self.context.location {
+            Some(ref location) => write!(f, "{}: {}", location, self.kind),
+            None => self.kind.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self.kind {
+            ErrorKind::LexingError(ref err) => Some(err),
+            ErrorKind::ParsingError(ref err) => Some(err),
+            ErrorKind::ExpressionError(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn test_display() {
+    let location = Location {
+        file: "shaders/pbr.glsl".to_string(),
+        line: 42,
+        column: 9,
+    };
+    assert_eq!(
+        Error::from(ParseError::MissingParameter("include".to_string()))
+            .or_at(location)
+            .to_string(),
+        "shaders/pbr.glsl:42:9: #include expects a file name"
+    );
+    assert_eq!(
+        Error::from(ErrorKind::ErrorDirective("unsupported".to_string()))
+            .or_at(Location {
+                file: "lights.glsl".to_string(),
+                line: 4,
+                column: 1,
+            })
+            .included_from(Location {
+                file: "a.glsl".to_string(),
+                line: 3,
+                column: 1,
+            })
+            .included_from(Location {
+                file: "main.glsl".to_string(),
+                line: 10,
+                column: 1,
+            })
+            .to_string(),
+        "in file included from a.glsl:3, from main.glsl:10:\nlights.glsl:4:1: #error unsupported"
+    );
+    assert_eq!(
+        Error::from(ErrorKind::ErrorDirective("unsupported".to_string())).to_string(),
+        "#error unsupported"
+    );
+    assert_eq!(
+        Error::from(ParseError::ElifAfterElse("elifdef".to_string())).to_string(),
+        "`#elifdef` after `#else`"
+    );
+    assert_eq!(
+        Error::from(ExpressionError::DivisionByZero).to_string(),
+        "division by zero"
+    );
+}
@@ -0,0 +1,444 @@
+use lexer::Token;
+use error::*;
+
+/// Value of an integer constant expression. As in C, `#if` arithmetic is done in the widest
+/// integer types and an unsigned operand turns the whole operation unsigned.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Value {
+    Signed(i64),
+    Unsigned(u64),
+}
+
+impl Value {
+    pub fn is_true(self) -> bool {
+        self.as_u64() != 0
+    }
+
+    fn from_bool(b: bool) -> Value {
+        Value::Signed(b as i64)
+    }
+
+    fn as_u64(self) -> u64 {
+        match self {
+            Value::Signed(v) => v as u64,
+            Value::Unsigned(v) => v,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum BinaryOperator {
+    Mul,
+    Div,
+    Rem,
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Eq,
+    Ne,
+    BitAnd,
+    BitXor,
+    BitOr,
+    And,
+    Or,
+}
+
+/// Evaluates an already macro-expanded `#if` expression.
+pub fn evaluate(tokens: &[Token]) -> Result<Value> {
+    let mut evaluator = Evaluator {
+        tokens,
+        position: 0,
+    };
+    let value = evaluator.conditional(true)?;
+    match evaluator.next() {
+        Some(token) => Err(ExpressionError::UnexpectedToken(token.formatted_str().into_owned()))?,
+        None => Ok(value),
+    }
+}
+
+struct Evaluator<'t, 'a: 't> {
+    tokens: &'t [Token<'a>],
+    position: usize,
+}
+
+impl<'t, 'a> Evaluator<'t, 'a> {
+    fn peek(&mut self) -> Option<&'t Token<'a>> {
+        while let Some(&Token::Whitespace) = self.tokens.get(self.position) {
+            self.position += 1;
+        }
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<&'t Token<'a>> {
+        let token = self.peek();
+        if token.is_some() {
+            self.position += 1;
+        }
+        token
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        match self.next() {
+            Some(&Token::Char(c)) if c == expected => Ok(()),
+            Some(token) => {
+                Err(ExpressionError::UnexpectedToken(token.formatted_str().into_owned()))?
+            }
+            None => Err(ExpressionError::UnexpectedEnd)?,
+        }
+    }
+
+    // Operands which are not evaluated (e.g. the right side of `0 && x`) are still parsed, but
+    // errors such as division by zero are only reported when `active` is set.
+    fn conditional(&mut self, active: bool) -> Result<Value> {
+        let condition = self.binary(1, active)?;
+        if let Some(&Token::Char('?')) = self.peek() {
+            self.position += 1;
+            let taken = condition.is_true();
+            let if_true = self.conditional(active && taken)?;
+            self.expect(':')?;
+            let if_false = self.conditional(active && !taken)?;
+            let value = if taken { if_true } else { if_false };
+            match (if_true, if_false) {
+                (Value::Signed(_), Value::Signed(_)) => Ok(value),
+                _ => Ok(Value::Unsigned(value.as_u64())),
+            }
+        } else {
+            Ok(condition)
+        }
+    }
+
+    fn binary(&mut self, min_precedence: u8, active: bool) -> Result<Value> {
+        let mut lhs = self.unary(active)?;
+        while let Some((operator, precedence)) = self.peek().and_then(binary_operator) {
+            if precedence < min_precedence {
+                break;
+            }
+            self.position += 1;
+            let rhs_active = match operator {
+                BinaryOperator::And => active && lhs.is_true(),
+                BinaryOperator::Or => active && !lhs.is_true(),
+                _ => active,
+            };
+            let rhs = self.binary(precedence + 1, rhs_active)?;
+            lhs = apply(operator, lhs, rhs, active)?;
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self, active: bool) -> Result<Value> {
+        match self.next() {
+            Some(&Token::Char('+')) => self.unary(active),
+            Some(&Token::Char('-')) => {
+                Ok(match self.unary(active)? {
+                    Value::Signed(v) => Value::Signed(v.wrapping_neg()),
+                    Value::Unsigned(v) => Value::Unsigned(v.wrapping_neg()),
+                })
+            }
+            Some(&Token::Char('~')) => {
+                Ok(match self.unary(active)? {
+                    Value::Signed(v) => Value::Signed(!v),
+                    Value::Unsigned(v) => Value::Unsigned(!v),
+                })
+            }
+            Some(&Token::Char('!')) => Ok(Value::from_bool(!self.unary(active)?.is_true())),
+            Some(&Token::Char('(')) => {
+                let value = self.conditional(active)?;
+                self.expect(')')?;
+                Ok(value)
+            }
+            Some(Token::Word(word)) => {
+                if word.starts_with(|c: char| c.is_ascii_digit()) {
+                    parse_integer_literal(word)
+                } else if word.starts_with('\'') {
+                    parse_character_literal(word)
+                } else {
+                    // Identifiers remaining after macro expansion evaluate to zero.
+                    Ok(Value::Signed(0))
+                }
+            }
+            Some(token) => {
+                Err(ExpressionError::UnexpectedToken(token.formatted_str().into_owned()))?
+            }
+            None => Err(ExpressionError::UnexpectedEnd)?,
+        }
+    }
+}
+
+fn binary_operator(token: &Token) -> Option<(BinaryOperator, u8)> {
+    use self::BinaryOperator::*;
+    let operator = match *token {
+        Token::Char('*') => Mul,
+        Token::Char('/') => Div,
+        Token::Char('%') => Rem,
+        Token::Char('+') => Add,
+        Token::Char('-') => Sub,
+        Token::Char('<') => Lt,
+        Token::Char('>') => Gt,
+        Token::Char('&') => BitAnd,
+        Token::Char('^') => BitXor,
+        Token::Char('|') => BitOr,
+        Token::Punctuator(ref p) => {
+            match p.as_ref() {
+                "<<" => Shl,
+                ">>" => Shr,
+                "<=" => Le,
+                ">=" => Ge,
+                "==" => Eq,
+                "!=" => Ne,
+                "&&" => And,
+                "||" => Or,
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    let precedence = match operator {
+        Mul | Div | Rem => 10,
+        Add | Sub => 9,
+        Shl | Shr => 8,
+        Lt | Gt | Le | Ge => 7,
+        Eq | Ne => 6,
+        BitAnd => 5,
+        BitXor => 4,
+        BitOr => 3,
+        And => 2,
+        Or => 1,
+    };
+    Some((operator, precedence))
+}
+
+fn apply(operator: BinaryOperator, lhs: Value, rhs: Value, active: bool) -> Result<Value> {
+    use self::BinaryOperator::*;
+    match operator {
+        And => return Ok(Value::from_bool(lhs.is_true() && rhs.is_true())),
+        Or => return Ok(Value::from_bool(lhs.is_true() || rhs.is_true())),
+        Div | Rem if rhs.as_u64() == 0 => {
+            if active {
+                Err(ExpressionError::DivisionByZero)?
+            } else {
+                return Ok(Value::Signed(0));
+            }
+        }
+        // The result of a shift has the type of its left operand.
+        Shl | Shr => {
+            let amount = rhs.as_u64().min(u32::MAX as u64) as u32;
+            return Ok(match (operator, lhs) {
+                (Shl, Value::Signed(a)) => Value::Signed(a.checked_shl(amount).unwrap_or(0)),
+                (Shl, Value::Unsigned(a)) => Value::Unsigned(a.checked_shl(amount).unwrap_or(0)),
+                (_, Value::Signed(a)) => {
+                    Value::Signed(a.checked_shr(amount).unwrap_or(if a < 0 { -1 } else { 0 }))
+                }
+                (_, Value::Unsigned(a)) => Value::Unsigned(a.checked_shr(amount).unwrap_or(0)),
+            });
+        }
+        _ => {}
+    }
+
+    Ok(match (lhs, rhs) {
+        (Value::Signed(a), Value::Signed(b)) => {
+            match operator {
+                Mul => Value::Signed(a.wrapping_mul(b)),
+                Div => Value::Signed(a.wrapping_div(b)),
+                Rem => Value::Signed(a.wrapping_rem(b)),
+                Add => Value::Signed(a.wrapping_add(b)),
+                Sub => Value::Signed(a.wrapping_sub(b)),
+                Lt => Value::from_bool(a < b),
+                Gt => Value::from_bool(a > b),
+                Le => Value::from_bool(a <= b),
+                Ge => Value::from_bool(a >= b),
+                Eq => Value::from_bool(a == b),
+                Ne => Value::from_bool(a != b),
+                BitAnd => Value::Signed(a & b),
+                BitXor => Value::Signed(a ^ b),
+                BitOr => Value::Signed(a | b),
+                Shl | Shr | And | Or => unreachable!(),
+            }
+        }
+        (a, b) => {
+            let (a, b) = (a.as_u64(), b.as_u64());
+            match operator {
+                Mul => Value::Unsigned(a.wrapping_mul(b)),
+                Div => Value::Unsigned(a / b),
+                Rem => Value::Unsigned(a % b),
+                Add => Value::Unsigned(a.wrapping_add(b)),
+                Sub => Value::Unsigned(a.wrapping_sub(b)),
+                Lt => Value::from_bool(a < b),
+                Gt => Value::from_bool(a > b),
+                Le => Value::from_bool(a <= b),
+                Ge => Value::from_bool(a >= b),
+                Eq => Value::from_bool(a == b),
+                Ne => Value::from_bool(a != b),
+                BitAnd => Value::Unsigned(a & b),
+                BitXor => Value::Unsigned(a ^ b),
+                BitOr => Value::Unsigned(a | b),
+                Shl | Shr | And | Or => unreachable!(),
+            }
+        }
+    })
+}
+
+/// Parses a decimal, hexadecimal, octal or binary integer literal with an optional `u`/`l`/`ll`
+/// suffix. Literals too large for a signed value become unsigned.
+fn parse_integer_literal(literal: &str) -> Result<Value> {
+    let invalid = || ExpressionError::InvalidLiteral(literal.to_string());
+
+    let suffix_start = literal
+        .find(['u', 'U', 'l', 'L'])
+        .unwrap_or(literal.len());
+    let (body, suffix) = literal.split_at(suffix_start);
+    let unsigned = match suffix.to_lowercase().as_str() {
+        "" | "l" | "ll" => false,
+        "u" | "ul" | "lu" | "ull" | "llu" => true,
+        _ => Err(invalid())?,
+    };
+
+    let body = body.replace('\'', "");
+    let (digits, radix) = if body.starts_with("0x") || body.starts_with("0X") {
+        (&body[2..], 16)
+    } else if body.starts_with("0b") || body.starts_with("0B") {
+        (&body[2..], 2)
+    } else if body.len() > 1 && body.starts_with('0') {
+        (&body[1..], 8)
+    } else {
+        (&body[..], 10)
+    };
+
+    match u64::from_str_radix(digits, radix) {
+        Ok(value) if unsigned || value > i64::MAX as u64 => Ok(Value::Unsigned(value)),
+        Ok(value) => Ok(Value::Signed(value as i64)),
+        Err(_) => Err(invalid())?,
+    }
+}
+
+/// Parses a character literal like `'a'`, `'\n'`, `'\101'` or `'\x41'` to the value of the
+/// character.
+fn parse_character_literal(literal: &str) -> Result<Value> {
+    let invalid = || ExpressionError::InvalidLiteral(literal.to_string());
+
+    let body = &literal[1..literal.len() - 1];
+    let mut chars = body.chars();
+    let value = match chars.next() {
+        Some('\\') => {
+            let rest = chars.as_str();
+            let (value, escape_length) = match chars.next() {
+                Some('n') => (u32::from('\n'), 1),
+                Some('t') => (u32::from('\t'), 1),
+                Some('r') => (u32::from('\r'), 1),
+                Some('a') => (7, 1),
+                Some('b') => (8, 1),
+                Some('f') => (12, 1),
+                Some('v') => (11, 1),
+                Some(c @ '\\') | Some(c @ '\'') | Some(c @ '"') | Some(c @ '?') => {
+                    (u32::from(c), 1)
+                }
+                Some('x') => {
+                    let digits = rest[1..].len();
+                    let value = u32::from_str_radix(&rest[1..], 16).map_err(|_| invalid())?;
+                    (value, digits + 1)
+                }
+                Some('0'..='7') => {
+                    let digits = rest.chars().take(3).take_while(|c| c.is_digit(8)).count();
+                    let value = u32::from_str_radix(&rest[..digits], 8).map_err(|_| invalid())?;
+                    (value, digits)
+                }
+                _ => Err(invalid())?,
+            };
+            if escape_length != rest.len() {
+                Err(invalid())?
+            }
+            value
+        }
+        Some(c) if chars.as_str().is_empty() => u32::from(c),
+        _ => Err(invalid())?,
+    };
+    Ok(Value::Signed(i64::from(value)))
+}
+
+#[cfg(test)]
+fn evaluate_str(code: &str) -> Result<Value> {
+    evaluate(&::lexer::tokenize(code).unwrap())
+}
+
+#[test]
+fn test_evaluate_arithmetic() {
+    assert_eq!(evaluate_str("1 + 2 * 3"), Ok(Value::Signed(7)));
+    assert_eq!(evaluate_str("(1 + 2) * 3"), Ok(Value::Signed(9)));
+    assert_eq!(evaluate_str("7 / 2 - 7 % 2"), Ok(Value::Signed(2)));
+    assert_eq!(evaluate_str("-3 + +1"), Ok(Value::Signed(-2)));
+    assert_eq!(evaluate_str("1 << 4 | 0x0F & ~3"), Ok(Value::Signed(28)));
+    assert_eq!(evaluate_str("5 ^ 1"), Ok(Value::Signed(4)));
+}
+
+#[test]
+fn test_evaluate_comparison_and_logic() {
+    assert_eq!(evaluate_str("2 >= 2 && 1 != 2"), Ok(Value::Signed(1)));
+    assert_eq!(evaluate_str("1 < 0 || !1"), Ok(Value::Signed(0)));
+    assert_eq!(evaluate_str("0 ? 1 : 2 ? 3 : 4"), Ok(Value::Signed(3)));
+    assert_eq!(evaluate_str("UNDEFINED == 0"), Ok(Value::Signed(1)));
+}
+
+#[test]
+fn test_evaluate_literals() {
+    assert_eq!(evaluate_str("0x1F"), Ok(Value::Signed(31)));
+    assert_eq!(evaluate_str("017"), Ok(Value::Signed(15)));
+    assert_eq!(evaluate_str("0b101"), Ok(Value::Signed(5)));
+    assert_eq!(evaluate_str("10UL"), Ok(Value::Unsigned(10)));
+    assert_eq!(evaluate_str("10ll"), Ok(Value::Signed(10)));
+    assert_eq!(evaluate_str("0xFFFFFFFFFFFFFFFF"), Ok(Value::Unsigned(u64::MAX)));
+    assert_eq!(
+        evaluate_str("1.0").map_err(|err| err.kind),
+        Err(ErrorKind::ExpressionError(ExpressionError::InvalidLiteral("1.0".to_string())))
+    );
+    assert_eq!(
+        evaluate_str("09").map_err(|err| err.kind),
+        Err(ErrorKind::ExpressionError(ExpressionError::InvalidLiteral("09".to_string())))
+    );
+    assert_eq!(evaluate_str("'A' == 65"), Ok(Value::Signed(1)));
+    assert_eq!(evaluate_str("'\\n'"), Ok(Value::Signed(10)));
+    assert_eq!(evaluate_str("'\\''"), Ok(Value::Signed(39)));
+    assert_eq!(evaluate_str("'\\\\'"), Ok(Value::Signed(92)));
+    assert_eq!(evaluate_str("'\\0' + '\\101' + '\\x41'"), Ok(Value::Signed(130)));
+    assert_eq!(evaluate_str("' '"), Ok(Value::Signed(32)));
+    assert_eq!(
+        evaluate_str("'ab'").map_err(|err| err.kind),
+        Err(ErrorKind::ExpressionError(ExpressionError::InvalidLiteral("'ab'".to_string())))
+    );
+    assert_eq!(
+        evaluate_str("'\\q'").map_err(|err| err.kind),
+        Err(ErrorKind::ExpressionError(ExpressionError::InvalidLiteral("'\\q'".to_string())))
+    );
+    assert_eq!(
+        evaluate_str("'\\12é'").map_err(|err| err.kind),
+        Err(ErrorKind::ExpressionError(ExpressionError::InvalidLiteral("'\\12é'".to_string())))
+    );
+}
+
+#[test]
+fn test_evaluate_unsigned() {
+    assert_eq!(evaluate_str("-1 > 0u"), Ok(Value::Signed(1)));
+    assert_eq!(evaluate_str("-1 > 0"), Ok(Value::Signed(0)));
+    assert_eq!(evaluate_str("1 ? -1 : 0u"), Ok(Value::Unsigned(u64::MAX)));
+}
+
+#[test]
+fn test_evaluate_errors() {
+    assert_eq!(
+        evaluate_str("1 / 0").map_err(|err| err.kind),
+        Err(ErrorKind::ExpressionError(ExpressionError::DivisionByZero))
+    );
+    assert_eq!(evaluate_str("0 && 1 / 0"), Ok(Value::Signed(0)));
+    assert_eq!(evaluate_str("1 || 1 % 0"), Ok(Value::Signed(1)));
+    assert_eq!(
+        evaluate_str("(1 + 2").map_err(|err| err.kind),
+        Err(ErrorKind::ExpressionError(ExpressionError::UnexpectedEnd))
+    );
+    assert_eq!(
+        evaluate_str("1 2").map_err(|err| err.kind),
+        Err(ErrorKind::ExpressionError(ExpressionError::UnexpectedToken("2".to_string())))
+    );
+}
@@ -7,7 +7,9 @@ pub enum Token<'a> {
     Word(Cow<'a, str>),
     PreprocessorDirective(Cow<'a, str>),
     Comment,
+    Whitespace,
     String(Cow<'a, str>),
+    Punctuator(Cow<'a, str>),
     Newline { with_escape: bool },
     Char(char),
 }
@@ -15,59 +17,301 @@ pub enum Token<'a> {
 
 impl<'a> Token<'a> {
     pub fn formatted_str(&'a self) -> Cow<'a, str> {
+        match *self {
+            Token::Word(ref s) => Cow::Borrowed(s),
+            Token::PreprocessorDirective(ref s) => Cow::Owned(format!("#{}", s)),
+            Token::Comment => Cow::Borrowed(""),
+            Token::Whitespace => Cow::Borrowed(" "),
+            Token::String(ref s) => Cow::Owned(format!("\"{}\"", s)),
+            Token::Punctuator(ref s) => Cow::Borrowed(s),
+            Token::Newline { with_escape: false } => Cow::Borrowed("\n"),
+            Token::Newline { with_escape: true } => Cow::Borrowed("\\\n"),
+            Token::Char(s) => Cow::Owned(format!("{}", s)),
+        }
+    }
+
+    pub fn into_owned(self) -> Token<'static> {
         match self {
-            &Token::Word(ref s) => Cow::Borrowed(s),
-            &Token::PreprocessorDirective(ref s) => Cow::Owned(format!("#{}", s)),
-            &Token::Comment => Cow::Borrowed(""),
-            &Token::String(ref s) => Cow::Owned(format!("\"{}\"", s)),
-            &Token::Newline { with_escape: false } => Cow::Borrowed("\n"),
-            &Token::Newline { with_escape: true } => Cow::Borrowed("\\\n"),
-            &Token::Char(s) => Cow::Owned(format!("{}", s)),
+            Token::Word(s) => Token::Word(Cow::Owned(s.into_owned())),
+            Token::PreprocessorDirective(s) => {
+                Token::PreprocessorDirective(Cow::Owned(s.into_owned()))
+            }
+            Token::Comment => Token::Comment,
+            Token::Whitespace => Token::Whitespace,
+            Token::String(s) => Token::String(Cow::Owned(s.into_owned())),
+            Token::Punctuator(s) => Token::Punctuator(Cow::Owned(s.into_owned())),
+            Token::Newline { with_escape } => Token::Newline { with_escape },
+            Token::Char(c) => Token::Char(c),
+        }
+    }
+}
+
+/// Position of a token in the code. Lines and columns are counted from 1, columns in characters.
+/// The file is an id given by the processor to each file it lexes, 0 for the processed code.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Span {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+    pub file: usize,
+}
+
+impl Span {
+    /// The beginning of the code of the file with id 0.
+    pub fn start() -> Span {
+        Span {
+            offset: 0,
+            line: 1,
+            column: 1,
+            file: 0,
+        }
+    }
+
+    /// The position after the given text, which starts at this position.
+    fn advance(self, text: &str) -> Span {
+        text.chars().fold(Span { offset: self.offset + text.len(), ..self }, |span, c| {
+            if c == '\n' {
+                Span { line: span.line + 1, column: 1, ..span }
+            } else {
+                Span { column: span.column + 1, ..span }
+            }
+        })
+    }
+
+    /// The location in code of an unknown file, which is set by the processor.
+    pub fn location(self) -> Location {
+        Location {
+            file: String::new(),
+            line: self.line,
+            column: self.column,
         }
     }
 }
 
+/// Splits code into tokens. Comments are replaced by whitespace, and whitespace is only reported
+/// between two tokens on the same line.
 pub fn tokenize<'a>(code: &'a str) -> Result<Vec<Token<'a>>> {
+    Ok(tokenize_spanned(code)?.into_iter().map(|(token, _)| token).collect())
+}
+
+/// Like `tokenize`, but gives the position of each token as well, in the file with id 0.
+pub fn tokenize_spanned<'a>(code: &'a str) -> Result<Vec<(Token<'a>, Span)>> {
+    tokenize_file(code, 0)
+}
+
+/// Like `tokenize_spanned`, with the spans in the file with the given id.
+pub fn tokenize_file<'a>(code: &'a str, file: usize) -> Result<Vec<(Token<'a>, Span)>> {
+    let (tokens, mut errors) = tokenize_recovering(code, file);
+    if errors.is_empty() {
+        Ok(tokens)
+    } else {
+        Err(errors.swap_remove(0))
+    }
+}
+
+/// Like `tokenize_file`, but skips to the next line after an error, and gives all the errors.
+pub fn tokenize_recovering<'a>(code: &'a str, file: usize) -> (Vec<(Token<'a>, Span)>, Vec<Error>) {
     let mut code = code;
-    let mut ret = Vec::new();
-    while code.len() > 0 {
-        let remaining_code = match parse_token(code.as_bytes()) {
-            IResult::Done(rest, token) => {
-                let rest = ::std::str::from_utf8(rest).unwrap();
+    let mut ret: Vec<(Token, Span)> = Vec::new();
+    let mut errors = Vec::new();
+    let mut span = Span { file, ..Span::start() };
+    let mut space = None;
+    // Set inside the `<file>` header name of `#include`, `#include_next` or `#embed`, where a
+    // backslash is just a character, as in `<dir\file.h>`.
+    let mut header_name = false;
+    while !code.is_empty() {
+        let last = ret.last().map(|(token, _)| token);
+        // Directives are only recognized at the beginning of a logical line, elsewhere `#` is a
+        // stringizing operator or just a character.
+        let line_start = matches!(last, None | Some(&Token::Newline { with_escape: false }));
+        let lexed = match lex_token(code, line_start) {
+            Err(LexingError::StrayBackslash) if header_name => Ok((Token::Char('\\'), 1)),
+            lexed => lexed,
+        };
+        let remaining_code = match lexed {
+            Ok((token, length)) => {
                 match token {
-                    Token::Comment => {}
+                    Token::Comment | Token::Whitespace => {
+                        space = space.or(Some(span));
+                    }
                     token => {
-                        ret.push(token);
+                        header_name = match token {
+                            Token::Char('<') => takes_header_name(&ret),
+                            Token::Char('>') | Token::Newline { .. } => false,
+                            _ => header_name,
+                        };
+                        let line_start = matches!(last, None | Some(&Token::Newline { .. }));
+                        let newline = matches!(token, Token::Newline { .. });
+                        if let Some(space) = space {
+                            if !line_start && !newline {
+                                ret.push((Token::Whitespace, space));
+                            }
+                        }
+                        space = None;
+                        ret.push((token, span));
                     }
                 }
-                rest
+                &code[length..]
+            }
+            Err(err) => {
+                errors.push(Error::from(err).or_at(span.location()));
+                space = None;
+                match code.find('\n') {
+                    Some(newline) => {
+                        let newline_span = span.advance(&code[..newline]);
+                        ret.push((Token::Newline { with_escape: false }, newline_span));
+                        &code[newline + 1..]
+                    }
+                    None => break,
+                }
             }
-            IResult::Error(_) |
-            IResult::Incomplete(_) => Err(Error::LexingError)?,
         };
+        span = span.advance(&code[..code.len() - remaining_code.len()]);
         code = remaining_code;
     }
-    Ok(ret)
-}
-
-named!(parse_token<Token>,
-    do_parse!(
-        take_while!( |c| c == b' ' || c == b'\t' ) >>
-        token: alt!(
-            parse_preproc |
-            parse_comment_line |
-            parse_comment_multiline |
-            parse_string |
-            parse_nl |
-            parse_word |
-            parse_char
-        ) >>
-        take_while!( |c| c == b' ' || c == b'\t' ) >>
-        (token)
+    (ret, errors)
+}
+
+/// Checks if the tokens end with a directive followed by a header name.
+fn takes_header_name(tokens: &[(Token, Span)]) -> bool {
+    let mut tokens = tokens.iter().rev().skip_while(|(token, _)| *token == Token::Whitespace);
+    match tokens.next() {
+        Some((Token::PreprocessorDirective(name), _)) => {
+            matches!(name.as_ref(), "include" | "include_next" | "embed")
+        }
+        _ => false,
+    }
+}
+
+/// Decodes code read as bytes. Invalid UTF-8 is replaced to carry on with, and gives an error
+/// located at the first invalid byte.
+pub fn decode(code: Vec<u8>) -> (String, Option<Error>) {
+    match String::from_utf8(code) {
+        Ok(code) => (code, None),
+        Err(err) => {
+            let valid = err.utf8_error().valid_up_to();
+            let code = err.into_bytes();
+            let span = Span::start().advance(::std::str::from_utf8(&code[..valid]).unwrap());
+            let err = Error::from(LexingError::InvalidUtf8).or_at(span.location());
+            (String::from_utf8_lossy(&code).into_owned(), Some(err))
+        }
+    }
+}
+
+/// A token and its length in bytes.
+type Lexed<'a> = ::std::result::Result<(Token<'a>, usize), LexingError>;
+
+/// Lexes the token `code` starts with.
+fn lex_token(code: &str, line_start: bool) -> Lexed<'_> {
+    if let Some(lexed) = lex_delimited(code) {
+        return lexed;
+    }
+    let parsed = if line_start {
+        parse_token(code.as_bytes())
+    } else {
+        parse_line_token(code.as_bytes())
+    };
+    match parsed {
+        IResult::Done(rest, token) => Ok((token, code.len() - rest.len())),
+        // Any other character is a token of its own.
+        _ => {
+            let c = code.chars().next().unwrap();
+            Ok((Token::Char(c), c.len_utf8()))
+        }
+    }
+}
+
+/// Lexes the tokens which have to be terminated: string and character literals, block comments
+/// and escaped newlines. Gives `None` if `code` starts with another token.
+fn lex_delimited(code: &str) -> Option<Lexed<'_>> {
+    let lexed = match code.as_bytes()[0] {
+        b'"' => {
+            literal_length(code)
+                .map(|length| (Token::String(Cow::Borrowed(&code[1..length - 1])), length))
+                .ok_or(LexingError::UnterminatedString)
+        }
+        // Character literals are kept as words, like numbers.
+        b'\'' => {
+            literal_length(code)
+                .map(|length| (Token::Word(Cow::Borrowed(&code[..length])), length))
+                .ok_or(LexingError::UnterminatedCharacter)
+        }
+        b'/' if code.starts_with("/*") => {
+            code[2..]
+                .find("*/")
+                .map(|end| (Token::Comment, end + 4))
+                .ok_or(LexingError::UnterminatedComment)
+        }
+        b'\\' => {
+            let rest = code[1..].trim_start_matches([' ', '\t']);
+            let newline = if rest.starts_with('\n') {
+                1
+            } else if rest.starts_with("\r\n") {
+                2
+            } else {
+                return Some(Err(LexingError::StrayBackslash));
+            };
+            Ok((Token::Newline { with_escape: true }, code.len() - rest.len() + newline))
+        }
+        _ => return None,
+    };
+    Some(lexed)
+}
+
+/// The length of the string or character literal `code` starts with, up to and including the
+/// closing quote, or `None` if the literal doesn't end on the same line. Newlines can be escaped.
+fn literal_length(code: &str) -> Option<usize> {
+    let quote = code.as_bytes()[0];
+    let mut i = 1;
+    while i < code.len() {
+        match code.as_bytes()[i] {
+            b'\\' if code[i + 1..].starts_with("\r\n") => i += 2,
+            b'\\' => i += 1,
+            b'\n' => return None,
+            c if c == quote => return Some(i + 1),
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Lexes `code` as exactly one token, which is needed to check the result of token pasting.
+pub fn tokenize_single(code: &str) -> Option<Token<'_>> {
+    if code.is_empty() {
+        return None;
+    }
+    match lex_token(code, false) {
+        Ok((Token::Comment, _)) | Ok((Token::Whitespace, _)) | Ok((Token::Newline { .. }, _)) => {
+            None
+        }
+        Ok((token, length)) if length == code.len() => Some(token),
+        _ => None,
+    }
+}
+
+named!(parse_token<Token<'a>>,
+    alt!(
+        parse_preproc |
+        parse_line_token
+    )
+);
+
+named!(parse_line_token<Token<'a>>,
+    alt!(
+        parse_whitespace |
+        parse_comment_line |
+        parse_nl |
+        parse_word |
+        parse_punctuator
     )
 );
 
-named!(parse_word<Token>,
+named!(parse_whitespace<Token<'a>>,
+    map!(take_while1!( |c| c == b' ' || c == b'\t' ), |_| Token::Whitespace)
+);
+
+named!(parse_word<Token<'a>>,
         map!(
             map_res!(
                 take_while1!(|c| is_alphanumeric(c) || c == b'.' || c == b'\'' || c == b'_'),
@@ -78,39 +322,35 @@ named!(parse_word<Token>,
 );
 
 
-named!(parse_comment_line<Token>,
-       map!(delimited!( tag!("//"), take_until!("\n"), peek!(tag!("\n")) ), |_| Token::Comment ));
+// Ends before the newline, or at the end of the code.
+named!(parse_comment_line<Token<'a>>,
+       map!(preceded!( tag!("//"), take_till!(|c| c == b'\n') ), |_| Token::Comment ));
 
-named!(parse_comment_multiline<Token>,
-       map!(delimited!( tag!("/*"), take_until!("*/"), tag!("*/") ), |_| Token::Comment ));
-
-named!(parse_string<Token>, map!(
-            map_res!(delimited!(tag!("\""), take_until!("\""), tag!("\"")), ::std::str::from_utf8),
-            |s| Token::String(Cow::Borrowed(s))
-        )
+named!(parse_punctuator<Token<'a>>,
+    map!(
+        map_res!(
+            alt_complete!(
+                tag!("<<=") | tag!(">>=") | tag!("##") |
+                tag!("->") | tag!("++") | tag!("--") | tag!("<<") | tag!(">>") |
+                tag!("<=") | tag!(">=") | tag!("==") | tag!("!=") | tag!("&&") | tag!("||") |
+                tag!("*=") | tag!("/=") | tag!("%=") | tag!("+=") | tag!("-=") |
+                tag!("&=") | tag!("^=") | tag!("|=")
+            ),
+            ::std::str::from_utf8
+        ),
+        |p| Token::Punctuator(Cow::Borrowed(p))
+    )
 );
-named!(parse_char<Token>, map!(anychar, |c| Token::Char(c)));
-named!(parse_nl<Token>,
-        alt!(
-            map!(
-                do_parse!(
-                    tag!("\\") >>
-                    take_while!(|c| c == b' ' || c == b'\t') >>
-                    alt!( tag!("\n") | tag!("\r\n") ) >>
-                    ()
-                ),
-                |s| Token::Newline{with_escape:true}
-            ) |
-            map!(
-                alt!( tag!("\n") | tag!("\r\n") ),
-                |s| Token::Newline{with_escape:false}
-            )
+named!(parse_nl<Token<'a>>,
+        map!(
+            alt!( tag!("\n") | tag!("\r\n") ),
+            |_| Token::Newline{with_escape:false}
         )
 );
-named!(parse_preproc<Token>,
+named!(parse_preproc<Token<'a>>,
     map!(
         map_res!( do_parse!(
-            t: tag!("#") >>
+            tag!("#") >>
             p: take_while!(|c| is_alphanumeric(c) || c == b'_') >>
             (p)
         ), ::std::str::from_utf8),
@@ -134,28 +374,72 @@ void frag() {
 ";
 
     assert_eq!(tokenize(code), Ok(vec![
-        PreprocessorDirective(Cow::Borrowed("include")), String(Cow::Borrowed("header.h")),
-        Newline{with_escape: false},
+        PreprocessorDirective(Cow::Borrowed("include")), Whitespace,
+        String(Cow::Borrowed("header.h")), Newline{with_escape: false},
 
-        PreprocessorDirective(Cow::Borrowed("define")), Word(Cow::Borrowed("TEST")),
-        Word(Cow::Borrowed("1.0f")), Newline{with_escape: false},
+        PreprocessorDirective(Cow::Borrowed("define")), Whitespace, Word(Cow::Borrowed("TEST")),
+        Whitespace, Word(Cow::Borrowed("1.0f")), Newline{with_escape: false},
 
         Newline{with_escape: false},
 
         Newline{with_escape: false},
 
-        Word(Cow::Borrowed("void")), Word(Cow::Borrowed("frag")), Char('('), Char(')'), Char('{'),
-        Newline{with_escape: false},
+        Word(Cow::Borrowed("void")), Whitespace, Word(Cow::Borrowed("frag")), Char('('),
+        Char(')'), Whitespace, Char('{'), Newline{with_escape: false},
 
-        Word(Cow::Borrowed("gl_Frag")), Char('='), Word(Cow::Borrowed("vec4")), Char('('),
-        Word(Cow::Borrowed("vec3")), Char('('), Word(Cow::Borrowed("1")), Char(','),
-        Word(Cow::Borrowed("1")), Char(','), Word(Cow::Borrowed("1")), Char(')'), Char('*'),
-        Word(Cow::Borrowed("TEST")), Char(','), Word(Cow::Borrowed("1")), Char(')'), Char(';'),
-        Newline{with_escape: false},
+        Word(Cow::Borrowed("gl_Frag")), Whitespace, Char('='), Whitespace,
+        Word(Cow::Borrowed("vec4")), Char('('), Word(Cow::Borrowed("vec3")), Char('('),
+        Word(Cow::Borrowed("1")), Char(','), Word(Cow::Borrowed("1")), Char(','),
+        Word(Cow::Borrowed("1")), Char(')'), Whitespace, Char('*'), Whitespace,
+        Word(Cow::Borrowed("TEST")), Char(','), Whitespace, Word(Cow::Borrowed("1")), Char(')'),
+        Char(';'), Newline{with_escape: false},
 
         Char('}'), Newline{with_escape: false}
     ]));
 
+    assert_eq!(tokenize("a/* comment */b \\\n    c"), Ok(vec![
+        Word(Cow::Borrowed("a")), Whitespace, Word(Cow::Borrowed("b")),
+        Newline{with_escape: true}, Word(Cow::Borrowed("c")),
+    ]));
+    assert_eq!(tokenize("a // b\nc // d"), Ok(vec![
+        Word(Cow::Borrowed("a")), Newline{with_escape: false}, Word(Cow::Borrowed("c")),
+    ]));
+    assert_eq!(tokenize("//"), Ok(vec![]));
+}
+
+#[test]
+fn test_tokenize_spanned() {
+    use self::Token::*;
+    let span = |offset, line, column| Span { offset, line, column, file: 0 };
+    assert_eq!(tokenize_spanned("a /* x\n */ b\n  c"), Ok(vec![
+        (Word(Cow::Borrowed("a")), span(0, 1, 1)), (Whitespace, span(1, 1, 2)),
+        (Word(Cow::Borrowed("b")), span(11, 2, 5)), (Newline{with_escape: false}, span(12, 2, 6)),
+        (Word(Cow::Borrowed("c")), span(15, 3, 3)),
+    ]));
+    assert_eq!(tokenize("a\n /*").map_err(|err| err.context.location),
+               Err(Some(Location { file: "".to_string(), line: 2, column: 2 })));
+}
+
+#[test]
+fn test_tokenize_recovering() {
+    let (tokens, errors) = tokenize_recovering("a\n\"", 1);
+    assert_eq!(tokens, vec![
+        (Token::Word(Cow::Borrowed("a")), Span { file: 1, ..Span::start() }),
+        (Token::Newline{with_escape: false}, Span { offset: 1, line: 1, column: 2, file: 1 }),
+    ]);
+    assert_eq!(errors.into_iter().map(|err| err.context.location).collect::<Vec<_>>(),
+               vec![Some(Location { file: "".to_string(), line: 2, column: 1 })]);
+
+    let (tokens, errors) = tokenize_recovering("a /* b\nc \\ d\ne", 0);
+    assert_eq!(tokens.into_iter().map(|(token, _)| token).collect::<Vec<_>>(), vec![
+        Token::Word(Cow::Borrowed("a")), Token::Newline{with_escape: false},
+        Token::Word(Cow::Borrowed("c")), Token::Newline{with_escape: false},
+        Token::Word(Cow::Borrowed("e")),
+    ]);
+    assert_eq!(errors.into_iter().map(|err| err.kind).collect::<Vec<_>>(), vec![
+        ::error::ErrorKind::LexingError(LexingError::UnterminatedComment),
+        ::error::ErrorKind::LexingError(LexingError::StrayBackslash),
+    ]);
 }
 
 #[test]
@@ -168,7 +452,7 @@ fn test_token() {
     {
         let code = "#include \"header.h\"";
         assert_eq!(parse_token(code.as_bytes()),
-                   IResult::Done("\"header.h\"".as_bytes(),
+                   IResult::Done(" \"header.h\"".as_bytes(),
                                  Token::PreprocessorDirective(Cow::Borrowed("include"))
                    )
         );
@@ -182,6 +466,41 @@ fn test_word() {
     assert_eq!(parse_word("hello, world".as_bytes()),
                IResult::Done(", world".as_bytes(), Token::Word(Cow::Borrowed("hello")) ));
 }
+#[test]
+fn test_punctuator() {
+    assert_eq!(parse_token("<<= 1".as_bytes()),
+               IResult::Done(" 1".as_bytes(), Token::Punctuator(Cow::Borrowed("<<="))));
+    assert_eq!(tokenize("a>=-b"), Ok(vec![
+        Token::Word(Cow::Borrowed("a")), Token::Punctuator(Cow::Borrowed(">=")),
+        Token::Char('-'), Token::Word(Cow::Borrowed("b")),
+    ]));
+}
+
+#[test]
+fn test_preproc_line_start() {
+    assert_eq!(tokenize("#define S(x) #x ## y\\\n#z"), Ok(vec![
+        Token::PreprocessorDirective(Cow::Borrowed("define")), Token::Whitespace,
+        Token::Word(Cow::Borrowed("S")), Token::Char('('), Token::Word(Cow::Borrowed("x")),
+        Token::Char(')'), Token::Whitespace, Token::Char('#'), Token::Word(Cow::Borrowed("x")),
+        Token::Whitespace, Token::Punctuator(Cow::Borrowed("##")), Token::Whitespace,
+        Token::Word(Cow::Borrowed("y")), Token::Newline{with_escape: true}, Token::Char('#'),
+        Token::Word(Cow::Borrowed("z")),
+    ]));
+    assert_eq!(tokenize("a\n  #if"), Ok(vec![
+        Token::Word(Cow::Borrowed("a")), Token::Newline{with_escape: false},
+        Token::PreprocessorDirective(Cow::Borrowed("if")),
+    ]));
+}
+
+#[test]
+fn test_tokenize_single() {
+    assert_eq!(tokenize_single("u_color"), Some(Token::Word(Cow::Borrowed("u_color"))));
+    assert_eq!(tokenize_single("<<="), Some(Token::Punctuator(Cow::Borrowed("<<="))));
+    assert_eq!(tokenize_single("#define"), None);
+    assert_eq!(tokenize_single("+-"), None);
+    assert_eq!(tokenize_single("//"), None);
+}
+
 #[test]
 fn test_comment_line() {
 
@@ -192,22 +511,58 @@ and some code";
 
     assert_eq!(parse_comment_line(code.as_bytes()),
                IResult::Done("\nand some code".as_bytes(), Token::Comment ));
-    assert_eq!(parse_comment_line(code2.as_bytes()), IResult::Error(ErrorKind::Tag));
+    assert_eq!(parse_comment_line(code2.as_bytes()), IResult::Error(::nom::ErrorKind::Tag));
 }
 #[test]
 fn test_comment_multiline() {
     let code = "/* jsdfoisjd \
     fsd , #! f */ and then some";
-    assert_eq!(parse_comment_multiline(code.as_bytes()),
-               IResult::Done(" and then some".as_bytes(), Token::Comment));
+    assert_eq!(lex_delimited(code),
+               Some(Ok((Token::Comment, code.len() - " and then some".len()))));
+    assert_eq!(lex_delimited("/* never\n ends *"), Some(Err(LexingError::UnterminatedComment)));
 }
 
 #[test]
 fn test_string() {
     let code = "\"rabadaba\" and then some()";
-    assert_eq!(parse_string(code.as_bytes()),
-               IResult::Done(" and then some()".as_bytes(),
-                             Token::String(Cow::Borrowed("rabadaba"))));
+    assert_eq!(lex_delimited(code), Some(Ok((Token::String(Cow::Borrowed("rabadaba")), 10))));
+    let code = "\"say \\\"hi\\\" \\\n\"";
+    assert_eq!(lex_delimited(code),
+               Some(Ok((Token::String(Cow::Borrowed("say \\\"hi\\\" \\\n")), code.len()))));
+    assert_eq!(lex_delimited("\"line\nbreak\""), Some(Err(LexingError::UnterminatedString)));
+    assert_eq!(lex_delimited("'\\''"), Some(Ok((Token::Word(Cow::Borrowed("'\\''")), 4))));
+    assert_eq!(lex_delimited("'a"), Some(Err(LexingError::UnterminatedCharacter)));
+}
+
+#[test]
+fn test_lexing_errors() {
+    let error = |err, line, column| {
+        Err(Error::from(err).or_at(Location { file: "".to_string(), line, column }))
+    };
+    assert_eq!(tokenize("a\n  \"b"), error(LexingError::UnterminatedString, 2, 3));
+    assert_eq!(tokenize("x = 'b;"), error(LexingError::UnterminatedCharacter, 1, 5));
+    assert_eq!(tokenize("a /* b\nc"), error(LexingError::UnterminatedComment, 1, 3));
+    assert_eq!(tokenize("a \\ b\n"), error(LexingError::StrayBackslash, 1, 3));
+    assert_eq!(tokenize("a \\"), error(LexingError::StrayBackslash, 1, 3));
+    assert_eq!(tokenize("a <b\\c>"), error(LexingError::StrayBackslash, 1, 5));
+    assert_eq!(tokenize("#include <a> \\"), error(LexingError::StrayBackslash, 1, 14));
+    assert_eq!(tokenize("#include <dir\\file.h>\n"), Ok(vec![
+        Token::PreprocessorDirective(Cow::Borrowed("include")), Token::Whitespace, Token::Char('<'),
+        Token::Word(Cow::Borrowed("dir")), Token::Char('\\'), Token::Word(Cow::Borrowed("file.h")),
+        Token::Char('>'), Token::Newline{with_escape: false},
+    ]));
+    assert_eq!(tokenize("\u{e9} \"\u{e9}\""), Ok(vec![
+        Token::Char('\u{e9}'), Token::Whitespace, Token::String(Cow::Borrowed("\u{e9}")),
+    ]));
+
+    let (code, err) = decode(b"ok\n \xff\n".to_vec());
+    assert_eq!(code, "ok\n \u{fffd}\n");
+    assert_eq!(err, Some(Error::from(LexingError::InvalidUtf8).or_at(Location {
+        file: "".to_string(),
+        line: 2,
+        column: 2,
+    })));
+    assert_eq!(decode(b"ok".to_vec()), ("ok".to_string(), None));
 }
 
 #[test]
@@ -1,10 +1,18 @@
 #![recursion_limit="128"]
-#[macro_use]
 extern crate nom;
 
+mod diagnostic;
 mod error;
+mod expression;
 mod lexer;
+mod loader;
+mod macros;
 mod parser;
 mod processor;
 
-pub use processor::process;
+pub use diagnostic::{Diagnostic, Severity, Style};
+pub use error::{Error, ErrorContext, ErrorKind, ExpressionError, LexingError, Location, Note,
+                ParseError, Result};
+pub use loader::FileLoader;
+pub use processor::{process, BuiltinContext, Define, PragmaAction, ProcessOutput, Processor,
+                    Warning};
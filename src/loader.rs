@@ -0,0 +1,33 @@
+/// Loads the files included by the processed code. Implemented for closures which return the
+/// contents of the named file.
+pub trait FileLoader {
+    /// Returns the contents of the named file, or `None` if it can't be opened.
+    fn load(&self, name: &str) -> Option<String>;
+
+    /// Returns the bytes of the named file for `#embed` and `#include`, or `None` if it can't be
+    /// opened. Included files have to be valid UTF-8. Defaults to the bytes of the loaded text.
+    fn load_bytes(&self, name: &str) -> Option<Vec<u8>> {
+        self.load(name).map(String::into_bytes)
+    }
+
+    /// Checks if the named file exists, when searching for an included file. Defaults to loading
+    /// the file.
+    fn exists(&self, name: &str) -> bool {
+        self.load(name).is_some()
+    }
+
+    /// Returns an id of the file the name refers to, used to recognize a file which is included
+    /// again, possibly by a different name, e.g. by `#pragma once`. Defaults to the name itself.
+    fn canonical_id(&self, name: &str) -> String {
+        name.to_string()
+    }
+}
+
+impl<F> FileLoader for F
+where
+    F: Fn(&str) -> Option<String>,
+{
+    fn load(&self, name: &str) -> Option<String> {
+        self(name)
+    }
+}
@@ -0,0 +1,663 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::Deref;
+use std::rc::Rc;
+
+use lexer;
+use lexer::{Span, Token};
+use error::*;
+
+/// A macro definition. Function-like macros have a list of parameters, object-like macros don't.
+/// The variable arguments of a variadic macro are the last parameter, `__VA_ARGS__`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Macro {
+    pub params: Option<Vec<String>>,
+    pub variadic: bool,
+    pub body: Vec<Token<'static>>,
+    /// Where the macro was defined, `None` for predefined macros.
+    pub location: Option<Location>,
+}
+
+impl Macro {
+    pub fn new(params: Option<Vec<Cow<str>>>, value: Vec<Token>) -> Macro {
+        let variadic = params
+            .as_ref()
+            .and_then(|params| params.last())
+            .is_some_and(|param| param == "...");
+        let params = params.map(|params| {
+            params
+                .iter()
+                .map(|param| if param == "..." { "__VA_ARGS__" } else { param })
+                .map(|param| param.to_string())
+                .collect()
+        });
+        // Line continuations inside the definition are just whitespace.
+        let body = value
+            .into_iter()
+            .map(|token| match token {
+                Token::Newline { .. } => Token::Whitespace,
+                token => token.into_owned(),
+            })
+            .collect();
+        Macro {
+            params,
+            variadic,
+            body,
+            location: None,
+        }
+    }
+
+    /// Adds a note pointing at the definition to an error from expanding the macro.
+    fn defined_here(&self, err: Error) -> Error {
+        err.with_note("macro defined here".to_string(), self.location.as_ref())
+    }
+}
+
+/// The names of the macros whose expansion produced a token. A macro is never expanded again
+/// from a token in its own hide set, which makes recursive definitions like `#define foo foo`
+/// expand once and stop.
+type HideSet = Rc<HashSet<String>>;
+
+/// A token waiting to be expanded, with its position. Tokens from a macro body are located at
+/// the invocation of the macro.
+type PendingToken<'a> = (Token<'a>, Span, HideSet);
+
+type Pending<'a> = VecDeque<PendingToken<'a>>;
+
+pub type Builtins<'d> = dyn Fn(&str, Span) -> Result<Option<Vec<Token<'static>>>> + 'd;
+
+/// Expands macros using a set of definitions.
+pub struct Expander<'d> {
+    pub defines: &'d HashMap<String, Macro>,
+    /// Enables the GNU extension where `, ## __VA_ARGS__` removes the comma if there are no
+    /// variable arguments.
+    pub gnu_comma_paste: bool,
+    /// Gives the replacement of a built-in macro like `__LINE__` at the given position, or `None`
+    /// if the name isn't a built-in. The replacement isn't rescanned.
+    pub builtins: Box<Builtins<'d>>,
+    /// Gives the location of a position, where the errors from expanding a macro invoked there
+    /// are reported.
+    pub locate: Box<dyn Fn(Span) -> Location + 'd>,
+}
+
+impl<'d> Expander<'d> {
+    /// Expands all macros in `tokens`, rescanning the results for more macros to expand.
+    pub fn expand<'a>(&self, tokens: &[(Token<'a>, Span)]) -> Result<Vec<Token<'a>>> {
+        let empty = HideSet::default();
+        let input = tokens.iter().map(|(token, span)| (token.clone(), *span, empty.clone()));
+        let output = self.expand_pending(input.collect())?;
+        Ok(output.into_iter().map(|(token, _, _)| token).collect())
+    }
+
+    fn expand_pending<'a>(&self, mut input: Pending<'a>) -> Result<Vec<PendingToken<'a>>> {
+        let mut output = Vec::new();
+
+        while let Some((token, span, hide_set)) = input.pop_front() {
+            // Errors from expanding a macro are reported where it's invoked.
+            let located = |err: Error| err.or_at((self.locate)(span));
+            let definition = match token {
+                Token::Word(ref name) if !hide_set.contains(name.deref()) => {
+                    self.defines.get(name.deref())
+                }
+                _ => None,
+            };
+            let (replacement, hide_set) = match definition {
+                Some(definition) if definition.params.is_none() => {
+                    let body = &definition.body[..];
+                    let replacement =
+                        self.substitute(definition, body, span, &[], &mut []).map_err(located)?;
+                    (replacement, hide_set)
+                }
+                Some(definition) if starts_invocation(&input) => {
+                    let name = token.formatted_str();
+                    let param_count = definition.params.as_ref().map_or(0, |params| params.len());
+                    let max_args = if definition.variadic {
+                        param_count
+                    } else {
+                        usize::MAX
+                    };
+                    let (mut args, closing) = self
+                        .collect_arguments(&mut input, name.deref(), max_args)
+                        .map_err(|err| located(definition.defined_here(err)))?;
+                    let args = match (param_count, args.len()) {
+                        // `F()` passes a single empty argument, which is fine for a macro without
+                        // parameters.
+                        (0, 1) if args[0].is_empty() => vec![],
+                        (expected, given) if expected == given => args,
+                        // The variable arguments may be left out altogether.
+                        (expected, given) if definition.variadic && given + 1 == expected => {
+                            args.push(Pending::new());
+                            args
+                        }
+                        _ => {
+                            let err = ErrorKind::MacroArgumentCountMismatch(name.to_string());
+                            Err(located(definition.defined_here(err.into())))?
+                        }
+                    };
+                    let mut expanded_args = vec![None; args.len()];
+                    let body = &definition.body[..];
+                    let replacement = self
+                        .substitute(definition, body, span, &args[..], &mut expanded_args)
+                        .map_err(located)?;
+                    // Only the macros hiding both the name and the closing parenthesis stay
+                    // hidden in the replacement.
+                    (replacement, Rc::new(hide_set.intersection(&closing).cloned().collect()))
+                }
+                _ => {
+                    let builtin = match token {
+                        Token::Word(ref name) if definition.is_none() => {
+                            (self.builtins)(name.deref(), span).map_err(located)?
+                        }
+                        _ => None,
+                    };
+                    match builtin {
+                        Some(tokens) => {
+                            let tokens = tokens.into_iter();
+                            output.extend(tokens.map(|token| (token, span, hide_set.clone())))
+                        }
+                        None => output.push((token, span, hide_set)),
+                    }
+                    continue;
+                }
+            };
+
+            let mut names = (*hide_set).clone();
+            names.insert(token.formatted_str().into_owned());
+            let hide_set = Rc::new(names);
+            // The replacement is rescanned together with the rest of the input.
+            for (token, token_span, token_hide_set) in replacement.into_iter().rev() {
+                input.push_front((token, token_span, union(&token_hide_set, &hide_set)));
+            }
+        }
+
+        Ok(output)
+    }
+
+    /// Replaces the parameters in (a part of) a macro body with the arguments, and applies the
+    /// `#`, `##` and `__VA_OPT__` operators. Arguments are fully macro expanded first, unless
+    /// they are operands of `#` or `##`. Tokens from the arguments keep their positions and hide
+    /// sets, the others are located at the invocation `span`.
+    fn substitute<'a>(
+        &self,
+        definition: &Macro,
+        body: &[Token<'static>],
+        span: Span,
+        args: &[Pending<'a>],
+        expanded_args: &mut [Option<Vec<PendingToken<'a>>>],
+    ) -> Result<Vec<PendingToken<'a>>> {
+        let params = definition.params.as_ref().map_or(&[][..], |params| &params[..]);
+        let param_index = |token: &Token| match *token {
+            Token::Word(ref word) => params.iter().position(|param| param == word),
+            _ => None,
+        };
+        let va_opt = |token: &Token| {
+            definition.variadic && *token == Token::Word(Cow::Borrowed("__VA_OPT__"))
+        };
+        let va_index = params.len().wrapping_sub(1);
+        let paste = Token::Punctuator(Cow::Borrowed("##"));
+        let empty = HideSet::default();
+
+        let mut result: Vec<PendingToken<'a>> = Vec::with_capacity(body.len());
+        // Set after `##`, when the next operand has to be pasted to the end of the result.
+        let mut pasting = false;
+        // Set when the last operand was empty, which `##` treats as a placemarker.
+        let mut placemarker = false;
+        let mut i = 0;
+        while i < body.len() {
+            let token = &body[i];
+            let operand = if *token == paste {
+                while let Some(&(Token::Whitespace, _, _)) = result.last() {
+                    result.pop();
+                }
+                pasting = true;
+                i = skip_whitespace(body, i + 1);
+                continue;
+            } else if *token == Token::Whitespace {
+                result.push((Token::Whitespace, span, empty.clone()));
+                i += 1;
+                continue;
+            } else if *token == Token::Char('#') &&
+                       body.get(skip_whitespace(body, i + 1)).is_some_and(|next| {
+                           param_index(next).is_some() || va_opt(next)
+                       })
+            {
+                i = skip_whitespace(body, i + 1);
+                if let Some(index) = param_index(&body[i]) {
+                    let arg = args[index].iter().map(|(token, _, _)| token.clone());
+                    let arg = arg.collect::<Vec<_>>();
+                    vec![(stringize(&arg[..]), span, empty.clone())]
+                } else {
+                    let (content, end) = va_opt_content(body, i);
+                    i = end;
+                    let replaced = if self.is_empty_argument(args, expanded_args, va_index)? {
+                        vec![]
+                    } else {
+                        self.substitute(definition, content, span, args, expanded_args)?
+                    };
+                    let replaced = replaced.into_iter().map(|(token, _, _)| token);
+                    let replaced = replaced.collect::<Vec<_>>();
+                    vec![(stringize(&replaced[..]), span, empty.clone())]
+                }
+            } else if va_opt(token) {
+                let (content, end) = va_opt_content(body, i);
+                i = end;
+                if self.is_empty_argument(args, expanded_args, va_index)? {
+                    vec![]
+                } else {
+                    self.substitute(definition, content, span, args, expanded_args)?
+                }
+            } else if let Some(index) = param_index(token) {
+                let raw = || args[index].iter().cloned().collect();
+                if pasting && self.gnu_comma_paste && definition.variadic && index == va_index &&
+                    result.last().is_some_and(|(token, _, _)| *token == Token::Char(','))
+                {
+                    if args[index].is_empty() {
+                        result.pop();
+                    }
+                    pasting = false;
+                    raw()
+                } else if pasting || body.get(skip_whitespace(body, i + 1)) == Some(&paste) {
+                    raw()
+                } else {
+                    self.expand_argument(args, expanded_args, index)?.to_vec()
+                }
+            } else {
+                vec![(token.clone(), span, empty.clone())]
+            };
+            i += 1;
+
+            if pasting && !placemarker {
+                pasting = false;
+                let mut operand = operand.into_iter();
+                if let Some((right, _, _)) = operand.next() {
+                    // The parser rejects `##` without a left operand, but a definition could
+                    // still be built without it.
+                    let pasted = match result.pop() {
+                        Some((left, _, _)) => paste_tokens(&left, &right),
+                        None => Err(ParseError::MisplacedTokenPaste.into()),
+                    };
+                    let pasted = pasted.map_err(|err| definition.defined_here(err))?;
+                    result.push((pasted, span, empty.clone()));
+                }
+                result.extend(operand);
+            } else {
+                pasting = false;
+                placemarker = operand.is_empty();
+                result.extend(operand);
+            }
+        }
+        Ok(result)
+    }
+
+    /// Expands an argument the first time it's used, and keeps the result for other uses.
+    fn expand_argument<'e, 'a>(
+        &self,
+        args: &[Pending<'a>],
+        expanded_args: &'e mut [Option<Vec<PendingToken<'a>>>],
+        index: usize,
+    ) -> Result<&'e [PendingToken<'a>]> {
+        if expanded_args[index].is_none() {
+            expanded_args[index] = Some(self.expand_pending(args[index].clone())?);
+        }
+        Ok(expanded_args[index].as_ref().unwrap())
+    }
+
+    /// Whether an argument expands to no tokens, which decides if `__VA_OPT__` is replaced by its
+    /// content.
+    fn is_empty_argument<'a>(
+        &self,
+        args: &[Pending<'a>],
+        expanded_args: &mut [Option<Vec<PendingToken<'a>>>],
+        index: usize,
+    ) -> Result<bool> {
+        let expanded = self.expand_argument(args, expanded_args, index)?;
+        Ok(expanded.iter().all(|(token, _, _)| *token == Token::Whitespace))
+    }
+
+    /// Collects the arguments of a function-like macro invocation, which may span multiple lines.
+    /// Commas only separate arguments outside of nested parentheses, and only until `max_args`
+    /// arguments have been found, so that the variable arguments are collected as one. Returns the
+    /// arguments and the hide set of the closing parenthesis.
+    fn collect_arguments<'a>(
+        &self,
+        input: &mut Pending<'a>,
+        name: &str,
+        max_args: usize,
+    ) -> Result<(Vec<Pending<'a>>, HideSet)> {
+        while let Some((token, _, _)) = input.pop_front() {
+            if token == Token::Char('(') {
+                break;
+            }
+        }
+
+        let mut args = vec![Pending::new()];
+        let mut nesting = 0;
+        let closing;
+        loop {
+            let (token, span, hide_set) = match input.pop_front() {
+                Some(pending) => pending,
+                None => Err(ErrorKind::UnterminatedMacroInvocation(name.to_string()))?,
+            };
+            match token {
+                Token::Char(')') if nesting == 0 => {
+                    closing = hide_set;
+                    break;
+                }
+                Token::Char(',') if nesting == 0 && args.len() < max_args => {
+                    args.push(Pending::new());
+                    continue;
+                }
+                Token::Char('(') => nesting += 1,
+                Token::Char(')') => nesting -= 1,
+                _ => {}
+            }
+            let token = match token {
+                Token::Newline { .. } => Token::Whitespace,
+                token => token,
+            };
+            args.last_mut().unwrap().push_back((token, span, hide_set));
+        }
+
+        for arg in &mut args {
+            while let Some(&(Token::Whitespace, _, _)) = arg.front() {
+                arg.pop_front();
+            }
+            while let Some(&(Token::Whitespace, _, _)) = arg.back() {
+                arg.pop_back();
+            }
+        }
+        Ok((args, closing))
+    }
+}
+
+fn union(a: &HideSet, b: &HideSet) -> HideSet {
+    if a.is_empty() {
+        b.clone()
+    } else if b.is_empty() {
+        a.clone()
+    } else {
+        Rc::new(a.union(b).cloned().collect())
+    }
+}
+
+/// Checks if the next token, ignoring whitespace and newlines, opens an argument list.
+fn starts_invocation(input: &Pending) -> bool {
+    for (token, _, _) in input {
+        match *token {
+            Token::Whitespace |
+            Token::Newline { .. } => {}
+            Token::Char('(') => return true,
+            _ => return false,
+        }
+    }
+    false
+}
+
+/// Finds the parenthesized content of `__VA_OPT__` at `start`. Returns the content and the index
+/// of the closing parenthesis.
+fn va_opt_content<'b>(body: &'b [Token<'static>], start: usize) -> (&'b [Token<'static>], usize) {
+    let open = skip_whitespace(body, start + 1);
+    let mut nesting = 0;
+    for (i, token) in body.iter().enumerate().skip(open) {
+        match *token {
+            Token::Char('(') => nesting += 1,
+            Token::Char(')') if nesting == 1 => return (&body[open + 1..i], i),
+            Token::Char(')') => nesting -= 1,
+            _ => {}
+        }
+    }
+    (&body[body.len()..], body.len())
+}
+
+fn skip_whitespace(body: &[Token], mut i: usize) -> usize {
+    while let Some(&Token::Whitespace) = body.get(i) {
+        i += 1;
+    }
+    i
+}
+
+/// Spells out an argument as a string literal. Whitespace between the tokens becomes a single
+/// space, and quotes and backslashes in string literals are escaped.
+fn stringize(arg: &[Token]) -> Token<'static> {
+    let mut result = String::new();
+    let mut space = false;
+    for token in arg {
+        if *token == Token::Whitespace {
+            space = true;
+            continue;
+        }
+        if space {
+            result.push(' ');
+            space = false;
+        }
+        // Character literals are words starting with a quote.
+        let literal = match *token {
+            Token::String(_) => true,
+            Token::Word(ref word) => word.starts_with('\''),
+            _ => false,
+        };
+        if literal {
+            for c in token.formatted_str().chars() {
+                if c == '"' || c == '\\' {
+                    result.push('\\');
+                }
+                result.push(c);
+            }
+        } else {
+            result.push_str(token.formatted_str().deref());
+        }
+    }
+    Token::String(Cow::Owned(result))
+}
+
+/// Concatenates two tokens for the `##` operator. The result has to be a valid token.
+fn paste_tokens<'a>(left: &Token, right: &Token) -> Result<Token<'a>> {
+    let pasted = format!("{}{}", left.formatted_str(), right.formatted_str());
+    match lexer::tokenize_single(pasted.as_str()) {
+        Some(token) => Ok(token.into_owned()),
+        None => Err(ErrorKind::InvalidTokenPaste(pasted).into()),
+    }
+}
+
+#[cfg(test)]
+fn expand_str(code: &str, definitions: &str) -> Result<String> {
+    let mut defines = HashMap::new();
+    for item in ::parser::parse(::lexer::tokenize_spanned(definitions).unwrap()).unwrap() {
+        if let ::parser::Item::Define {
+            name,
+            params,
+            value,
+            ..
+        } = item
+        {
+            defines.insert(name.to_string(), Macro::new(params, value));
+        }
+    }
+    expand_with(code, &defines)
+}
+
+#[cfg(test)]
+fn expand_with(code: &str, defines: &HashMap<String, Macro>) -> Result<String> {
+    let tokens = ::lexer::tokenize_spanned(code).unwrap();
+    let expander = Expander {
+        defines,
+        gnu_comma_paste: true,
+        builtins: Box::new(|name, span| match name {
+            "__LINE__" => Ok(Some(vec![Token::Word(Cow::Owned(span.line.to_string()))])),
+            _ => Ok(None),
+        }),
+        locate: Box::new(Span::location),
+    };
+    let expanded = expander.expand(&tokens[..])?;
+    Ok(expanded.iter().map(|t| t.formatted_str().into_owned()).collect())
+}
+
+#[test]
+fn test_expand_object_like() {
+    let defines = "#define A B\n#define B 1";
+    assert_eq!(expand_str("A + B", defines), Ok("1 + 1".to_string()));
+}
+
+#[test]
+fn test_expand_function_like() {
+    let defines = "\
+#define SATURATE(x) clamp(x, 0.0, 1.0)
+#define MAX(a, b) ((a) > (b) ? (a) : (b))
+#define ZERO() 0
+#define ONE 1";
+    assert_eq!(
+        expand_str("SATURATE(v.x)", defines),
+        Ok("clamp(v.x, 0.0, 1.0)".to_string())
+    );
+    assert_eq!(
+        expand_str("MAX(ONE, f(2, 3))", defines),
+        Ok("((1) > (f(2, 3)) ? (1) : (f(2, 3)))".to_string())
+    );
+    assert_eq!(expand_str("ZERO() ZERO", defines), Ok("0 ZERO".to_string()));
+    assert_eq!(
+        expand_str("SATURATE\n(\n  a,\n)", defines).map_err(|err| err.kind),
+        Err(ErrorKind::MacroArgumentCountMismatch("SATURATE".to_string()))
+    );
+    assert_eq!(
+        expand_str("SATURATE(a", defines).map_err(|err| err.kind),
+        Err(ErrorKind::UnterminatedMacroInvocation("SATURATE".to_string()))
+    );
+    assert_eq!(
+        expand_str("a\n  ZERO + SATURATE(a, b)", defines).map_err(|err| err.context.location),
+        Err(Some(Location { file: String::new(), line: 2, column: 10 }))
+    );
+}
+
+#[test]
+fn test_expand_rescan() {
+    let defines = "\
+#define F G
+#define G(x) x + x
+#define H(x) F(x)";
+    assert_eq!(expand_str("F(1)", defines), Ok("1 + 1".to_string()));
+    assert_eq!(expand_str("H(F)", defines), Ok("G + G".to_string()));
+    assert_eq!(expand_str("G\n(2)", defines), Ok("2 + 2".to_string()));
+    assert_eq!(expand_str("G\n+", defines), Ok("G\n+".to_string()));
+}
+
+#[test]
+fn test_expand_stringize() {
+    let defines = "\
+#define LABEL(x) #x
+#define LABEL2(x) LABEL(x) # x
+#define A B";
+    assert_eq!(expand_str("LABEL(A)", defines), Ok("\"A\"".to_string()));
+    assert_eq!(expand_str("LABEL2(A)", defines), Ok("\"B\" \"A\"".to_string()));
+    assert_eq!(
+        expand_str("LABEL(  a+b   \n c  )", defines),
+        Ok("\"a+b c\"".to_string())
+    );
+    assert_eq!(
+        expand_str("LABEL(printf(\"%s\\n\"))", defines),
+        Ok("\"printf(\\\"%s\\\\n\\\")\"".to_string())
+    );
+    assert_eq!(expand_str("LABEL()", defines), Ok("\"\"".to_string()));
+    assert_eq!(expand_str("LABEL('\"' '\\n')", defines), Ok("\"'\\\"' '\\\\n'\"".to_string()));
+}
+
+#[test]
+fn test_expand_paste() {
+    let defines = "\
+#define UNIFORM(n) u_ ## n
+#define CAT(a, b) a##b
+#define CAT3(a, b, c) a ## b ## c
+#define OBJECT x ## 1
+#define color not_pasted
+#define u_color u_color_expanded";
+    assert_eq!(
+        expand_str("UNIFORM(color)", defines),
+        Ok("u_color_expanded".to_string())
+    );
+    assert_eq!(expand_str("CAT(<<, =) CAT(, b) CAT(a, )", defines), Ok("<<= b a".to_string()));
+    assert_eq!(expand_str("CAT3(a, , c) CAT3(,,)", defines), Ok("ac ".to_string()));
+    assert_eq!(expand_str("OBJECT", defines), Ok("x1".to_string()));
+    assert_eq!(
+        expand_str("CAT(+, -)", defines).map_err(|err| err.kind),
+        Err(ErrorKind::InvalidTokenPaste("+-".to_string()))
+    );
+}
+
+#[test]
+fn test_expand_variadic() {
+    let defines = "\
+#define LOG(fmt, ...) printf(fmt, __VA_ARGS__)
+#define GNU_LOG(fmt, ...) printf(fmt, ## __VA_ARGS__)
+#define OPT_LOG(fmt, ...) printf(fmt __VA_OPT__(,) __VA_ARGS__)
+#define ALL(...) [__VA_ARGS__] #__VA_ARGS__
+#define NAME(...) x ## __VA_OPT__(_ ## __VA_ARGS__) #__VA_OPT__(__VA_ARGS__)";
+    assert_eq!(
+        expand_str("LOG(\"%d %d\", 1, (2, 3))", defines),
+        Ok("printf(\"%d %d\", 1, (2, 3))".to_string())
+    );
+    assert_eq!(expand_str("LOG(\"\")", defines), Ok("printf(\"\", )".to_string()));
+    assert_eq!(expand_str("GNU_LOG(\"\")", defines), Ok("printf(\"\")".to_string()));
+    assert_eq!(expand_str("GNU_LOG(\"\", a)", defines), Ok("printf(\"\",a)".to_string()));
+    assert_eq!(expand_str("OPT_LOG(\"\")", defines), Ok("printf(\"\"  )".to_string()));
+    assert_eq!(expand_str("OPT_LOG(\"\", a)", defines), Ok("printf(\"\" , a)".to_string()));
+    assert_eq!(expand_str("ALL(a,b)", defines), Ok("[a,b] \"a,b\"".to_string()));
+    assert_eq!(expand_str("ALL()", defines), Ok("[] \"\"".to_string()));
+    assert_eq!(expand_str("NAME() NAME(y)", defines), Ok("x \"\" x_y \"y\"".to_string()));
+    assert_eq!(
+        expand_str("LOG()", "#define LOG(fmt, x, ...) fmt").map_err(|err| err.kind),
+        Err(ErrorKind::MacroArgumentCountMismatch("LOG".to_string()))
+    );
+
+    // The example of the C standard, where the variable arguments are empty if they expand to
+    // nothing.
+    let defines = "\
+#define F(...) f(0 __VA_OPT__(,) __VA_ARGS__)
+#define G(X, ...) f(0, X __VA_OPT__(,) __VA_ARGS__)
+#define SDEF(sname, ...) S sname __VA_OPT__(= { __VA_ARGS__ })
+#define EMP";
+    assert_eq!(
+        expand_str("F(a, b, c) F() F(EMP)", defines),
+        Ok("f(0 , a, b, c) f(0  ) f(0  )".to_string())
+    );
+    assert_eq!(
+        expand_str("G(a, b, c) G(a, ) G(a)", defines),
+        Ok("f(0, a , b, c) f(0, a  ) f(0, a  )".to_string())
+    );
+    assert_eq!(
+        expand_str("SDEF(foo); SDEF(bar, 1, 2);", defines),
+        Ok("S foo ; S bar = { 1, 2 };".to_string())
+    );
+
+    // The parser rejects `##` at the start of `__VA_OPT__`, but the expander mustn't panic on it.
+    let body = ::lexer::tokenize("a __VA_OPT__(## b)").unwrap();
+    let mut defines = HashMap::new();
+    defines.insert("F".to_string(), Macro::new(Some(vec![Cow::Borrowed("...")]), body));
+    assert_eq!(
+        expand_with("F(1)", &defines).map_err(|err| err.kind),
+        Err(ErrorKind::ParsingError(ParseError::MisplacedTokenPaste))
+    );
+}
+
+#[test]
+fn test_expand_recursive() {
+    let defines = "\
+#define foo foo
+#define bar baz
+#define baz bar
+#define errno (*errno_location(errno))
+#define f(a) a*g
+#define g(a) f(a)";
+    assert_eq!(expand_str("foo", defines), Ok("foo".to_string()));
+    assert_eq!(expand_str("bar baz", defines), Ok("bar baz".to_string()));
+    assert_eq!(expand_str("errno", defines), Ok("(*errno_location(errno))".to_string()));
+    assert_eq!(expand_str("f(2)(9)", defines), Ok("2*9*g".to_string()));
+    assert_eq!(expand_str("f(f(1))", defines), Ok("1*g*g".to_string()));
+}
+
+#[test]
+fn test_expand_builtin() {
+    let defines = "\
+#define LINE __LINE__
+#define F(x) x";
+    assert_eq!(expand_str("__LINE__\nLINE", defines), Ok("1\n2".to_string()));
+    assert_eq!(expand_str("F(\n__LINE__)\nF\n(__LINE__)", defines), Ok("2\n4".to_string()));
+}
@@ -1,5 +1,5 @@
 
-use lexer::Token;
+use lexer::{Span, Token};
 use error::*;
 use std::iter::Peekable;
 use std::borrow::Cow;
@@ -7,45 +7,186 @@ use std::ops::Deref;
 
 #[derive(Debug, PartialEq)]
 pub enum Item<'a> {
-    Text(Vec<Token<'a>>),
-    Include(Cow<'a, str>),
-    Define(Cow<'a, str>, Vec<Token<'a>>),
-    Undefine(Cow<'a, str>),
+    Text { tokens: Vec<(Token<'a>, Span)>, span: Span },
+    Include { header: HeaderName<'a>, span: Span },
+    IncludeNext { header: HeaderName<'a>, span: Span },
+    Embed { tokens: Vec<(Token<'a>, Span)>, span: Span },
+    Define {
+        name: Cow<'a, str>,
+        params: Option<Vec<Cow<'a, str>>>,
+        value: Vec<Token<'a>>,
+        span: Span,
+    },
+    Undefine { name: Cow<'a, str>, span: Span },
     Conditional {
-        define_name: Cow<'a, str>,
-        defined: Vec<Item<'a>>,
-        not_defined: Vec<Item<'a>>,
+        branches: Vec<(Condition<'a>, Vec<Item<'a>>)>,
+        else_branch: Vec<Item<'a>>,
+        span: Span,
     },
+    Error { message: String, span: Span },
+    Warning { message: String, span: Span },
+    /// `end` is the position of the newline ending the directive, which may span several
+    /// physical lines.
+    Line { tokens: Vec<(Token<'a>, Span)>, span: Span, end: Span },
+    Pragma { tokens: Vec<Token<'a>>, span: Span },
+}
+
+impl<'a> Item<'a> {
+    /// The position of the first token of a text, or of the directive.
+    pub fn span(&self) -> Span {
+        match *self {
+            Item::Text { span, .. } |
+            Item::Include { span, .. } |
+            Item::IncludeNext { span, .. } |
+            Item::Embed { span, .. } |
+            Item::Define { span, .. } |
+            Item::Undefine { span, .. } |
+            Item::Conditional { span, .. } |
+            Item::Error { span, .. } |
+            Item::Warning { span, .. } |
+            Item::Line { span, .. } |
+            Item::Pragma { span, .. } => span,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HeaderName<'a> {
+    /// `"file"`, searched first from the directory of the including file.
+    Quoted(Cow<'a, str>),
+    /// `<file>`, searched only from the include and system paths.
+    System(Cow<'a, str>),
+    /// Tokens which are macro expanded to either of the above.
+    Computed { tokens: Vec<(Token<'a>, Span)>, span: Span },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Condition<'a> {
+    Defined(Cow<'a, str>),
+    NotDefined(Cow<'a, str>),
+    Expression { tokens: Vec<(Token<'a>, Span)>, span: Span },
+}
+
+/// Parses the tokens given by `lexer::tokenize_spanned`. Errors are located at the token where
+/// they're found, without the file.
+pub fn parse<'a>(tokens: Vec<(Token<'a>, Span)>) -> Result<Vec<Item<'a>>> {
+    let mut i = Tokens::new(tokens, None);
+    match parse_block(&mut i, 0) {
+        Ok((result, _)) => Ok(result),
+        Err(err) => Err(err.or_at(i.span.location())),
+    }
+}
+
+/// Like `parse`, but skips to the next line after an error, and gives all the errors. An
+/// unterminated conditional is closed at the end.
+pub fn parse_recovering<'a>(tokens: Vec<(Token<'a>, Span)>) -> (Vec<Item<'a>>, Vec<Error>) {
+    let mut i = Tokens::new(tokens, Some(vec![]));
+    // Errors are only passed up in the strict mode.
+    let items = parse_block(&mut i, 0).map(|(items, _)| items).unwrap_or_default();
+    (items, i.errors.unwrap_or_default())
 }
 
-pub fn parse<'a>(tokens: Vec<Token<'a>>) -> Result<Vec<Item<'a>>> {
-    let i = tokens.into_iter();
-    let (result, _) = parse_block(&mut i.peekable(), 0)?;
-    Ok(result)
+/// Token stream which keeps the position of the last token. Items record the position of the
+/// token they start with.
+struct Tokens<I: Iterator> {
+    tokens: Peekable<I>,
+    span: Span,
+    // Set if the last token ended a line.
+    line_end: bool,
+    // The errors recovered from, `None` in the strict mode.
+    errors: Option<Vec<Error>>,
 }
 
+impl<'a, I> Tokens<I>
+where
+    I: Iterator<Item = (Token<'a>, Span)>,
+{
+    fn new<T>(tokens: T, errors: Option<Vec<Error>>) -> Tokens<I>
+    where
+        T: IntoIterator<Item = (Token<'a>, Span), IntoIter = I>,
+    {
+        Tokens {
+            tokens: tokens.into_iter().peekable(),
+            span: Span::start(),
+            line_end: true,
+            errors,
+        }
+    }
+
+    fn peek(&mut self) -> Option<&Token<'a>> {
+        self.tokens.peek().map(|(token, _)| token)
+    }
+
+    /// Gives the next token with its position.
+    fn next_spanned(&mut self) -> Option<(Token<'a>, Span)> {
+        self.next().map(|token| (token, self.span))
+    }
+
+    /// Fails with the error in the strict mode. Otherwise records the error and skips the rest
+    /// of the line.
+    fn recover(&mut self, err: Error) -> Result<()> {
+        let err = err.or_at(self.span.location());
+        match self.errors {
+            Some(ref mut errors) => errors.push(err),
+            None => return Err(err),
+        }
+        while !self.line_end && self.next().is_some() {}
+        Ok(())
+    }
+}
+
+impl<'a, I> Iterator for Tokens<I>
+where
+    I: Iterator<Item = (Token<'a>, Span)>,
+{
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        let (token, span) = self.tokens.next()?;
+        self.span = span;
+        self.line_end = token == Token::Newline { with_escape: false };
+        Some(token)
+    }
+}
+
+/// A directive closing a conditional block, with its position.
+type Closing<'a> = (Cow<'a, str>, Span);
+
+/// Parses items up to the end, or in a conditional block up to the closing directive, which is
+/// given with its position.
 fn parse_block<'a, I>(
-    i: &mut Peekable<I>,
+    i: &mut Tokens<I>,
     depth: i32,
-) -> Result<(Vec<Item<'a>>, Option<Cow<'a, str>>)>
+) -> Result<(Vec<Item<'a>>, Option<Closing<'a>>)>
 where
-    I: Iterator<Item = Token<'a>>,
+    I: Iterator<Item = (Token<'a>, Span)>,
 {
     let mut items = Vec::new();
     let directive = loop {
         match i.next() {
             Some(token) => {
+                let span = i.span;
                 let item = match token {
-                    Token::PreprocessorDirective(ref name) => {
-                        if depth > 0 && is_closing_directive(i, name.deref())? {
-                            break Some(name.clone());
-                        } else {
-                            parse_directive_as_item(name, i, depth)?
+                    Token::PreprocessorDirective(ref name) if depth > 0 => {
+                        match is_closing_directive(i, name.deref()) {
+                            Ok(true) => break Some((name.clone(), span)),
+                            Ok(false) => parse_directive_as_item(name, i, depth),
+                            // Only `#else` and `#endif` fail, and they still close the block.
+                            Err(err) => {
+                                i.recover(err)?;
+                                break Some((name.clone(), span));
+                            }
                         }
                     }
-                    _ => parse_text(token, i)?,
+                    Token::PreprocessorDirective(ref name) => {
+                        parse_directive_as_item(name, i, depth)
+                    }
+                    _ => parse_text(token, span, i),
                 };
-                items.push(item);
+                match item {
+                    Ok(item) => items.push(item),
+                    Err(err) => i.recover(err)?,
+                }
             }
             None => {
                 break None;
@@ -57,13 +198,14 @@ where
 
 }
 
-fn is_closing_directive<'a, I>(i: &mut Peekable<I>, name: &str) -> Result<bool>
+fn is_closing_directive<'a, I>(i: &mut Tokens<I>, name: &str) -> Result<bool>
 where
-    I: Iterator<Item = Token<'a>>,
+    I: Iterator<Item = (Token<'a>, Span)>,
 {
-    match name.deref() {
+    match name {
+        "elif" | "elifdef" | "elifndef" => Ok(true),
         "else" | "endif" => {
-            match i.next() {
+            match next_token(i) {
                 Some(Token::Newline { with_escape: false }) |
                 None => Ok(true),
                 _ => Err(ParseError::MissingNewline)?,
@@ -74,134 +216,468 @@ where
 }
 
 
-fn parse_text<'a, I>(first_token: Token<'a>, i: &mut Peekable<I>) -> Result<Item<'a>>
+fn parse_text<'a, I>(first_token: Token<'a>, span: Span, i: &mut Tokens<I>) -> Result<Item<'a>>
 where
-    I: Iterator<Item = Token<'a>>,
+    I: Iterator<Item = (Token<'a>, Span)>,
 {
-    let mut text = vec![first_token];
+    let mut text = vec![(first_token, span)];
     loop {
         if let Some(&Token::PreprocessorDirective(_)) = i.peek() {
             break;
         }
-        match i.next() {
+        match i.next_spanned() {
             Some(token) => {
-                text.push(token.clone());
+                text.push(token);
             }
             None => {
                 break;
             }
         }
     }
-    Ok(Item::Text(text))
+    Ok(Item::Text { tokens: text, span })
 }
 
 
-fn parse_directive_as_item<'a, I>(name: &str, i: &mut Peekable<I>, depth: i32) -> Result<Item<'a>>
+fn parse_directive_as_item<'a, I>(name: &str, i: &mut Tokens<I>, depth: i32) -> Result<Item<'a>>
 where
-    I: Iterator<Item = Token<'a>>,
+    I: Iterator<Item = (Token<'a>, Span)>,
 {
+    // The position of the directive.
+    let span = i.span;
     match name {
-        "if" | "elif" | "error" | "warning" | "line" => {
-            Err(ParseError::UnspportedPreprocessor(name.to_string()))?
+        "error" => {
+            Ok(Item::Error {
+                message: parse_message(i),
+                span,
+            })
+        }
+        "warning" => {
+            Ok(Item::Warning {
+                message: parse_message(i),
+                span,
+            })
+        }
+        "line" => {
+            let tokens = parse_line(i);
+            if tokens.is_empty() {
+                Err(ParseError::MissingParameter(name.to_string()))?
+            }
+            Ok(Item::Line { tokens, span, end: i.span })
+        }
+        "pragma" => {
+            Ok(Item::Pragma {
+                tokens: parse_raw_line(i),
+                span,
+            })
         }
         "include" => {
-            // TODO: Accept symbol as well
-            let filename = i.next();
-            if let Some(Token::String(s)) = filename {
-                Ok(Item::Include(s))
-            } else {
-                Err(ParseError::MissingParameter)?
+            Ok(Item::Include {
+                header: parse_header_name(i, name)?,
+                span,
+            })
+        }
+        "include_next" => {
+            Ok(Item::IncludeNext {
+                header: parse_header_name(i, name)?,
+                span,
+            })
+        }
+        "embed" => {
+            let tokens = parse_line(i);
+            if tokens.is_empty() {
+                Err(ParseError::MissingParameter(name.to_string()))?
             }
+            Ok(Item::Embed { tokens, span })
         }
-        "define" => parse_define(i),
+        "define" => parse_define(i, span),
         "undef" => {
-            let symbol = i.next();
+            let symbol = next_token(i);
             if let Some(Token::Word(ref s)) = symbol {
-                Ok(Item::Undefine(s.clone()))
+                Ok(Item::Undefine {
+                    name: s.clone(),
+                    span,
+                })
             } else {
-                Err(ParseError::MissingParameter)?
+                Err(ParseError::MissingParameter(name.to_string()))?
             }
         }
-        "ifdef" | "ifndef" => parse_conditional(i, name, depth),
-        "else" | "endif" => Err(ParseError::UnexpectedPreprocessor(name.to_string()))?,
+        "if" | "ifdef" | "ifndef" => parse_conditional(i, name, depth),
+        "elif" | "elifdef" | "elifndef" | "else" | "endif" => {
+            Err(ParseError::UnexpectedPreprocessor(name.to_string()))?
+        }
         _ => Err(ParseError::UnrecognizedPreprocessor(name.to_string()))?,
     }
 }
 
 fn parse_conditional<'a, I>(
-    i: &mut Peekable<I>,
+    i: &mut Tokens<I>,
     directive_name: &str,
     depth: i32,
 ) -> Result<Item<'a>>
 where
-    I: Iterator<Item = Token<'a>>,
+    I: Iterator<Item = (Token<'a>, Span)>,
 {
-    let symbol = i.next();
-    if let Some(Token::Word(symbol)) = symbol {
-        if let Some(Token::Newline { with_escape: false }) = i.next() {
-            let (items, closing_directive) = parse_block(i, depth + 1)?;
-            let items2 = match closing_directive.as_ref().map(|a| a.deref()) {
-                Some("endif") => vec![],
-                Some("else") => {
-                    let (items2, closing_directive) = parse_block(i, depth + 1)?;
-                    match closing_directive.as_ref().map(|a| a.deref()) {
-                        Some("endif") => items2,
-                        _ => Err(ParseError::ElseWithoutEndif)?,
-                    }
+    // The position of the `#if`, where an unterminated conditional is reported.
+    let span = i.span;
+    let mut branches = Vec::new();
+    let mut directive_name = directive_name;
+    loop {
+        let condition = match parse_condition(i, directive_name) {
+            Ok(condition) => condition,
+            Err(err) => {
+                // An invalid condition is false when recovering.
+                let condition_span = i.span;
+                i.recover(err)?;
+                Condition::Expression {
+                    tokens: vec![(Token::Word(Cow::Borrowed("0")), condition_span)],
+                    span: condition_span,
                 }
-                _ => Err(ParseError::IfWithoutEndif)?,
+            }
+        };
+        let (items, closing_directive) = parse_block(i, depth + 1)?;
+        branches.push((condition, items));
+        let (name, closing_span) = match closing_directive {
+            Some(closing_directive) => closing_directive,
+            None => {
+                i.recover(Error::from(ParseError::IfWithoutEndif).or_at(span.location()))?;
+                return Ok(Item::Conditional {
+                    branches,
+                    else_branch: vec![],
+                    span,
+                });
+            }
+        };
+        directive_name = match name.deref() {
+            "elif" => "elif",
+            "elifdef" => "elifdef",
+            "elifndef" => "elifndef",
+            "else" => {
+                let mut else_branch = Vec::new();
+                loop {
+                    let (items, closing_directive) = parse_block(i, depth + 1)?;
+                    else_branch.extend(items);
+                    let err = match closing_directive {
+                        Some((ref name, _)) if name == "endif" => break,
+                        Some((ref name, span)) if name.starts_with("elif") => {
+                            let err = ParseError::ElifAfterElse(name.to_string());
+                            Error::from(err).or_at(span.location())
+                        }
+                        Some(_) => {
+                            let err = Error::from(ParseError::ElseWithoutEndif);
+                            err.or_at(closing_span.location())
+                        }
+                        None => {
+                            let err = Error::from(ParseError::ElseWithoutEndif);
+                            i.recover(err.or_at(closing_span.location()))?;
+                            break;
+                        }
+                    };
+                    // The rest of the branch after a misplaced directive belongs to the `#else`.
+                    i.recover(err)?;
+                }
+                return Ok(Item::Conditional {
+                    branches,
+                    else_branch,
+                    span,
+                });
+            }
+            _ => {
+                return Ok(Item::Conditional {
+                    branches,
+                    else_branch: vec![],
+                    span,
+                });
+            }
+        };
+    }
+}
+
+fn parse_condition<'a, I>(i: &mut Tokens<I>, directive_name: &str) -> Result<Condition<'a>>
+where
+    I: Iterator<Item = (Token<'a>, Span)>,
+{
+    let span = i.span;
+    let missing_parameter = || ParseError::MissingParameter(directive_name.to_string());
+    match directive_name {
+        "ifdef" | "ifndef" | "elifdef" | "elifndef" => {
+            let symbol = match next_token(i) {
+                Some(Token::Word(symbol)) => symbol,
+                _ => Err(missing_parameter())?,
             };
+            match next_token(i) {
+                Some(Token::Newline { with_escape: false }) => {}
+                _ => Err(ParseError::MissingNewline)?,
+            }
+            if directive_name.ends_with("ifdef") {
+                Ok(Condition::Defined(symbol))
+            } else {
+                Ok(Condition::NotDefined(symbol))
+            }
+        }
+        "if" | "elif" => {
+            let tokens = parse_line(i);
+            if tokens.is_empty() {
+                Err(missing_parameter())?
+            }
+            Ok(Condition::Expression { tokens, span })
+        }
+        _ => unreachable!(),
+    }
+}
 
-            match directive_name {
-                "ifdef" => {
-                    Ok(Item::Conditional {
-                        define_name: symbol,
-                        defined: items,
-                        not_defined: items2,
-                    })
-                }
-                "ifndef" => {
-                    Ok(Item::Conditional {
-                        define_name: symbol,
-                        defined: items2,
-                        not_defined: items,
-                    })
+/// Collects the tokens up to the end of the logical line with their positions, dropping
+/// whitespace and escaped newlines.
+fn parse_line<'a, I>(i: &mut Tokens<I>) -> Vec<(Token<'a>, Span)>
+where
+    I: Iterator<Item = (Token<'a>, Span)>,
+{
+    let mut tokens = Vec::new();
+    while let Some((token, span)) = i.next_spanned() {
+        match token {
+            Token::Newline { with_escape: false } => break,
+            Token::Newline { with_escape: true } |
+            Token::Whitespace => {}
+            token => tokens.push((token, span)),
+        }
+    }
+    tokens
+}
+
+/// Spells out the rest of the logical line as the message of a diagnostic directive.
+fn parse_message<'a, I>(i: &mut I) -> String
+where
+    I: Iterator<Item = Token<'a>>,
+{
+    let mut message = String::new();
+    for token in i {
+        match token {
+            Token::Newline { with_escape: false } => break,
+            Token::Newline { with_escape: true } => message.push(' '),
+            token => message.push_str(token.formatted_str().deref()),
+        }
+    }
+    message.trim().to_string()
+}
+
+/// Collects the tokens up to the end of the logical line as they are, except for the whitespace
+/// at both ends.
+fn parse_raw_line<'a, I>(i: &mut I) -> Vec<Token<'a>>
+where
+    I: Iterator<Item = Token<'a>>,
+{
+    let mut tokens = Vec::new();
+    for token in i {
+        match token {
+            Token::Newline { with_escape: false } => {
+                break;
+            }
+            _ => {
+                tokens.push(token);
+            }
+        }
+    }
+    if let Some(&Token::Whitespace) = tokens.first() {
+        tokens.remove(0);
+    }
+    if let Some(&Token::Whitespace) = tokens.last() {
+        tokens.pop();
+    }
+    tokens
+}
+
+/// Parses a `"file"` or `<file>` header name. The characters between the angle brackets are
+/// taken as they are. Anything else is left to be macro expanded, up to the end of the line.
+fn parse_header_name<'a, I>(i: &mut Tokens<I>, directive_name: &str) -> Result<HeaderName<'a>>
+where
+    I: Iterator<Item = (Token<'a>, Span)>,
+{
+    let span = i.span;
+    let missing_parameter = || ParseError::MissingParameter(directive_name.to_string());
+    match next_token(i) {
+        Some(Token::String(name)) => Ok(HeaderName::Quoted(name)),
+        Some(Token::Char('<')) => {
+            let mut name = String::new();
+            loop {
+                match i.next() {
+                    Some(Token::Char('>')) => break,
+                    Some(Token::Newline { .. }) | None => Err(missing_parameter())?,
+                    Some(token) => name.push_str(token.formatted_str().deref()),
                 }
-                _ => unreachable!(),
             }
-        } else {
-            Err(ParseError::MissingNewline)?
+            Ok(HeaderName::System(Cow::Owned(name)))
+        }
+        Some(Token::Newline { with_escape: false }) | None => Err(missing_parameter())?,
+        Some(token) => {
+            let mut tokens = vec![(token, i.span)];
+            while !matches!(i.peek(), Some(&Token::Newline { with_escape: false }) | None) {
+                tokens.extend(i.next_spanned());
+            }
+            if let Some(&(Token::Whitespace, _)) = tokens.last() {
+                tokens.pop();
+            }
+            Ok(HeaderName::Computed { tokens, span })
         }
-    } else {
-        Err(ParseError::MissingParameter)?
     }
 }
 
+fn parse_define<'a, I>(i: &mut Tokens<I>, span: Span) -> Result<Item<'a>>
+where
+    I: Iterator<Item = (Token<'a>, Span)>,
+{
+    let symbol = next_token(i);
+    if let Some(Token::Word(name)) = symbol {
+        // A function-like macro has its parameter list right after the name, without whitespace
+        // in between.
+        let params = if let Some(&Token::Char('(')) = i.peek() {
+            i.next();
+            Some(parse_parameters(i)?)
+        } else {
+            None
+        };
+        let value = parse_raw_line(i);
+        Ok(Item::Define {
+            name,
+            params,
+            value,
+            span,
+        })
+    } else {
+        Err(ParseError::MissingParameter("define".to_string()))?
+    }
+}
 
-fn parse_define<'a, I>(i: &mut I) -> Result<Item<'a>>
+fn parse_parameters<'a, I>(i: &mut I) -> Result<Vec<Cow<'a, str>>>
 where
     I: Iterator<Item = Token<'a>>,
 {
-    let symbol = i.next();
-    if let Some(Token::Word(s)) = symbol {
-        let mut value = Vec::new();
-        loop {
-            if let Some(token) = i.next() {
-                match token {
-                    Token::Newline { with_escape: false } => {
-                        break;
-                    }
-                    _ => {
-                        value.push(token);
+    let mut params = Vec::new();
+    loop {
+        match next_token(i) {
+            Some(Token::Char(')')) if params.is_empty() => break,
+            // Variable arguments have to be the last parameter.
+            Some(Token::Word(ref param)) if param == "..." => {
+                params.push(param.clone());
+                match next_token(i) {
+                    Some(Token::Char(')')) => break,
+                    _ => Err(ParseError::InvalidMacroParameters)?,
+                }
+            }
+            Some(Token::Word(ref param)) if is_identifier(param) && param != "__VA_ARGS__" &&
+                                             !params.contains(param) => {
+                params.push(param.clone());
+                match next_token(i) {
+                    Some(Token::Char(',')) => {}
+                    Some(Token::Char(')')) => break,
+                    _ => Err(ParseError::InvalidMacroParameters)?,
+                }
+            }
+            _ => Err(ParseError::InvalidMacroParameters)?,
+        }
+    }
+    Ok(params)
+}
+
+fn is_identifier(word: &str) -> bool {
+    !word.starts_with(|c: char| c.is_ascii_digit()) &&
+        word.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+/// Checks the constraints on a macro replacement list: `##` can't be at either end, in
+/// function-like macros `#` has to be followed by a parameter, and `__VA_ARGS__` and
+/// `__VA_OPT__(...)` can only be used in variadic macros. This is left to processing the
+/// definition, as a definition in a group which isn't taken isn't checked.
+pub fn check_macro_body(params: Option<&[Cow<str>]>, value: &[Token]) -> Result<()> {
+    let tokens = value
+        .iter()
+        .filter(|token| !matches!(**token, Token::Whitespace | Token::Newline { .. }))
+        .collect::<Vec<_>>();
+
+    let paste = Token::Punctuator(Cow::Borrowed("##"));
+    if tokens.first() == Some(&&paste) || tokens.last() == Some(&&paste) {
+        Err(ParseError::MisplacedTokenPaste)?
+    }
+
+    let variadic = params.and_then(|params| params.last()).is_some_and(|param| param == "...");
+    let is_operand = |token: &Token| match *token {
+        Token::Word(ref word) => {
+            params.is_some_and(|params| params.contains(word)) ||
+                (variadic && (word == "__VA_ARGS__" || word == "__VA_OPT__"))
+        }
+        _ => false,
+    };
+
+    for (index, token) in tokens.iter().enumerate() {
+        match **token {
+            Token::Char('#') if params.is_some() &&
+                                 !tokens.get(index + 1).is_some_and(|next| is_operand(next)) => {
+                Err(ParseError::StringizeWithoutParameter)?
+            }
+            Token::Word(ref word) if word == "__VA_ARGS__" || word == "__VA_OPT__" => {
+                if !variadic {
+                    Err(ParseError::UnexpectedVariadicIdentifier)?
+                }
+                if word == "__VA_OPT__" {
+                    let close = closing_parenthesis(&tokens[index + 1..])
+                        .ok_or(ParseError::InvalidVaOpt)?;
+                    // Like the whole definition, the content can't start or end with `##`.
+                    let content = &tokens[index + 2..index + 1 + close];
+                    if content.first() == Some(&&paste) || content.last() == Some(&&paste) {
+                        Err(ParseError::InvalidVaOpt)?
                     }
                 }
-            } else {
-                break;
             }
+            _ => {}
         }
-        Ok(Item::Define(s, value))
-    } else {
-        Err(ParseError::MissingParameter)?
+    }
+    Ok(())
+}
+
+/// Gives the index of the closing parenthesis, if the tokens start with a balanced pair of
+/// parentheses.
+fn closing_parenthesis(tokens: &[&Token]) -> Option<usize> {
+    if tokens.first() != Some(&&Token::Char('(')) {
+        return None;
+    }
+    let mut nesting = 0;
+    for (index, token) in tokens.iter().enumerate() {
+        match **token {
+            Token::Char('(') => nesting += 1,
+            Token::Char(')') if nesting == 1 => return Some(index),
+            Token::Char(')') => nesting -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Returns the next token which is not whitespace.
+fn next_token<'a, I>(i: &mut I) -> Option<Token<'a>>
+where
+    I: Iterator<Item = Token<'a>>,
+{
+    i.find(|token| *token != Token::Whitespace)
+}
+
+/// The span of the line and column in the code.
+#[cfg(test)]
+fn span_at(code: &str, line: usize, column: usize) -> Span {
+    let offset = code.split('\n').take(line - 1).map(|line| line.len() + 1).sum::<usize>();
+    Span {
+        offset: offset + column - 1,
+        line,
+        column,
+        file: 0,
+    }
+}
+
+/// Parses a definition and checks its replacement list, as processing it does.
+#[cfg(test)]
+fn check_define(code: &str) -> Result<()> {
+    match parse(::lexer::tokenize_spanned(code).unwrap())?.pop() {
+        Some(Item::Define { params, value, .. }) => {
+            check_macro_body(params.as_ref().map(|p| &p[..]), &value[..])
+        }
+        item => panic!("expected a define, got {:?}", item),
     }
 }
 
@@ -220,26 +696,51 @@ undefined
 defined
 #endif";
 
-    let result = Ok(vec![
-        Item::Conditional {
-            define_name: Cow::Borrowed("TEST"),
-            defined: vec![
-                Item::Text(vec![
-                    Token::Word(Cow::Borrowed("defined")),
-                    Token::Newline { with_escape: false },
-                ]),
-            ],
-            not_defined: vec![
-                Item::Text(vec![
-                    Token::Word(Cow::Borrowed("undefined")),
-                    Token::Newline { with_escape: false },
-                ]),
-            ],
-        },
-    ]);
+    let branch = |code, condition, first, second| {
+        Ok(vec![
+            Item::Conditional {
+                branches: vec![
+                    (
+                        condition,
+                        vec![
+                            Item::Text {
+                                tokens: vec![
+                                    (Token::Word(Cow::Borrowed(first)), span_at(code, 2, 1)),
+                                    (
+                                        Token::Newline { with_escape: false },
+                                        span_at(code, 2, first.len() + 1),
+                                    ),
+                                ],
+                                span: span_at(code, 2, 1),
+                            },
+                        ]
+                    ),
+                ],
+                else_branch: vec![
+                    Item::Text {
+                        tokens: vec![
+                            (Token::Word(Cow::Borrowed(second)), span_at(code, 4, 1)),
+                            (
+                                Token::Newline { with_escape: false },
+                                span_at(code, 4, second.len() + 1),
+                            ),
+                        ],
+                        span: span_at(code, 4, 1),
+                    },
+                ],
+                span: span_at(code, 1, 1),
+            },
+        ])
+    };
 
-    assert_eq!(parse(::lexer::tokenize(code1).unwrap()), result);
-    assert_eq!(parse(::lexer::tokenize(code2).unwrap()), result);
+    assert_eq!(
+        parse(::lexer::tokenize_spanned(code1).unwrap()),
+        branch(code1, Condition::Defined(Cow::Borrowed("TEST")), "defined", "undefined")
+    );
+    assert_eq!(
+        parse(::lexer::tokenize_spanned(code2).unwrap()),
+        branch(code2, Condition::NotDefined(Cow::Borrowed("TEST")), "undefined", "defined")
+    );
 
 }
 
@@ -258,37 +759,197 @@ fn test_parse_conditional_nested() {
 
     let result = Ok(vec![
         Item::Conditional {
-            define_name: Cow::Borrowed("__TEST"),
-            defined: vec![
-                Item::Text(vec![
-                    Token::Word(Cow::Borrowed("section4")),
-                    Token::Newline { with_escape: false },
-                ]),
-                Item::Conditional {
-                    define_name: Cow::Borrowed("ANOTHER_TEST"),
-                    defined: vec![],
-                    not_defined: vec![
-                        Item::Text(vec![
-                            Token::Word(Cow::Borrowed("section1")),
-                            Token::Newline { with_escape: false },
-                        ]),
+            branches: vec![
+                (
+                    Condition::Defined(Cow::Borrowed("__TEST")),
+                    vec![
+                        Item::Text {
+                            tokens: vec![
+                                (Token::Word(Cow::Borrowed("section4")), span_at(code, 2, 5)),
+                                (Token::Newline { with_escape: false }, span_at(code, 2, 13)),
+                            ],
+                            span: span_at(code, 2, 5),
+                        },
+                        Item::Conditional {
+                            branches: vec![
+                                (
+                                    Condition::NotDefined(Cow::Borrowed("ANOTHER_TEST")),
+                                    vec![
+                                        Item::Text {
+                                            tokens: vec![
+                                                (
+                                                    Token::Word(Cow::Borrowed("section1")),
+                                                    span_at(code, 4, 9),
+                                                ),
+                                                (
+                                                    Token::Newline { with_escape: false },
+                                                    span_at(code, 4, 17),
+                                                ),
+                                            ],
+                                            span: span_at(code, 4, 9),
+                                        },
+                                    ]
+                                ),
+                            ],
+                            else_branch: vec![],
+                            span: span_at(code, 3, 5),
+                        },
+                        Item::Text {
+                            tokens: vec![
+                                (Token::Word(Cow::Borrowed("section2")), span_at(code, 6, 5)),
+                                (Token::Newline { with_escape: false }, span_at(code, 6, 13)),
+                            ],
+                            span: span_at(code, 6, 5),
+                        },
+                    ]
+                ),
+            ],
+            else_branch: vec![
+                Item::Text {
+                    tokens: vec![
+                        (Token::Word(Cow::Borrowed("section3")), span_at(code, 8, 5)),
+                        (Token::Newline { with_escape: false }, span_at(code, 8, 13)),
                     ],
+                    span: span_at(code, 8, 5),
                 },
-                Item::Text(vec![
-                    Token::Word(Cow::Borrowed("section2")),
-                    Token::Newline { with_escape: false },
-                ]),
-            ],
-            not_defined: vec![
-                Item::Text(vec![
-                    Token::Word(Cow::Borrowed("section3")),
-                    Token::Newline { with_escape: false },
-                ]),
             ],
+            span: span_at(code, 1, 1),
         },
     ]);
 
-    assert_eq!(parse(::lexer::tokenize(code).unwrap()), result);
+    assert_eq!(parse(::lexer::tokenize_spanned(code).unwrap()), result);
+}
+
+#[test]
+fn test_parse_if_elif() {
+    let code = "\
+#if QUALITY >= 2
+high
+#elif QUALITY \\
+    == 1
+medium
+#else
+low
+#endif";
+
+    let text = |word, line| {
+        vec![
+            Item::Text {
+                tokens: vec![
+                    (Token::Word(Cow::Borrowed(word)), span_at(code, line, 1)),
+                    (
+                        Token::Newline { with_escape: false },
+                        span_at(code, line, word.len() + 1),
+                    ),
+                ],
+                span: span_at(code, line, 1),
+            },
+        ]
+    };
+
+    assert_eq!(
+        parse(::lexer::tokenize_spanned(code).unwrap()),
+        Ok(vec![
+            Item::Conditional {
+                branches: vec![
+                    (
+                        Condition::Expression {
+                            tokens: vec![
+                                (Token::Word(Cow::Borrowed("QUALITY")), span_at(code, 1, 5)),
+                                (Token::Punctuator(Cow::Borrowed(">=")), span_at(code, 1, 13)),
+                                (Token::Word(Cow::Borrowed("2")), span_at(code, 1, 16)),
+                            ],
+                            span: span_at(code, 1, 1),
+                        },
+                        text("high", 2)
+                    ),
+                    (
+                        Condition::Expression {
+                            tokens: vec![
+                                (Token::Word(Cow::Borrowed("QUALITY")), span_at(code, 3, 7)),
+                                (Token::Punctuator(Cow::Borrowed("==")), span_at(code, 4, 5)),
+                                (Token::Word(Cow::Borrowed("1")), span_at(code, 4, 8)),
+                            ],
+                            span: span_at(code, 3, 1),
+                        },
+                        text("medium", 5)
+                    ),
+                ],
+                else_branch: text("low", 7),
+                span: span_at(code, 1, 1),
+            },
+        ])
+    );
+
+    assert_eq!(
+        parse(::lexer::tokenize_spanned("#if\nfoo\n#endif").unwrap()).map_err(|err| err.kind),
+        Err(ErrorKind::ParsingError(ParseError::MissingParameter("if".to_string())))
+    );
+    assert_eq!(
+        parse(::lexer::tokenize_spanned("foo\n  #if 1\nfoo\n").unwrap()),
+        Err(Error::from(ErrorKind::ParsingError(ParseError::IfWithoutEndif)).or_at(Location {
+            file: String::new(),
+            line: 2,
+            column: 3,
+        }))
+    );
+}
+
+#[test]
+fn test_parse_elifdef() {
+    let code = "\
+#ifdef VULKAN
+vulkan
+#elifndef GL
+none
+#elifdef GLES
+gles
+#endif";
+
+    let text = |word, line| {
+        vec![
+            Item::Text {
+                tokens: vec![
+                    (Token::Word(Cow::Borrowed(word)), span_at(code, line, 1)),
+                    (
+                        Token::Newline { with_escape: false },
+                        span_at(code, line, word.len() + 1),
+                    ),
+                ],
+                span: span_at(code, line, 1),
+            },
+        ]
+    };
+
+    assert_eq!(
+        parse(::lexer::tokenize_spanned(code).unwrap()),
+        Ok(vec![
+            Item::Conditional {
+                branches: vec![
+                    (Condition::Defined(Cow::Borrowed("VULKAN")), text("vulkan", 2)),
+                    (Condition::NotDefined(Cow::Borrowed("GL")), text("none", 4)),
+                    (Condition::Defined(Cow::Borrowed("GLES")), text("gles", 6)),
+                ],
+                else_branch: vec![],
+                span: span_at(code, 1, 1),
+            },
+        ])
+    );
+
+    assert_eq!(
+        parse(::lexer::tokenize_spanned("#if 1\n#else\n#elifdef GL\n#endif").unwrap())
+            .map_err(|err| err.kind),
+        Err(ErrorKind::ParsingError(ParseError::ElifAfterElse("elifdef".to_string())))
+    );
+    assert_eq!(
+        parse(::lexer::tokenize_spanned("#ifdef A\n#else\n#elif 1\n#endif").unwrap())
+            .map_err(|err| err.kind),
+        Err(ErrorKind::ParsingError(ParseError::ElifAfterElse("elif".to_string())))
+    );
+    assert_eq!(
+        parse(::lexer::tokenize_spanned("#elifndef GL\n").unwrap()).map_err(|err| err.kind),
+        Err(ErrorKind::ParsingError(ParseError::UnexpectedPreprocessor("elifndef".to_string())))
+    );
 }
 
 
@@ -296,8 +957,91 @@ fn test_parse_conditional_nested() {
 fn test_parse_include() {
     let code = "#include \"../test.h\"";
     assert_eq!(
-        parse(::lexer::tokenize(code).unwrap()),
-        Ok(vec![Item::Include(Cow::Borrowed("../test.h"))])
+        parse(::lexer::tokenize_spanned(code).unwrap()),
+        Ok(vec![
+            Item::Include {
+                header: HeaderName::Quoted(Cow::Borrowed("../test.h")),
+                span: span_at(code, 1, 1),
+            },
+        ])
+    );
+
+    let code = "#embed \"lut.bin\" limit(4)\n";
+    assert_eq!(
+        parse(::lexer::tokenize_spanned(code).unwrap()),
+        Ok(vec![
+            Item::Embed {
+                tokens: vec![
+                    (Token::String(Cow::Borrowed("lut.bin")), span_at(code, 1, 8)),
+                    (Token::Word(Cow::Borrowed("limit")), span_at(code, 1, 18)),
+                    (Token::Char('('), span_at(code, 1, 23)),
+                    (Token::Word(Cow::Borrowed("4")), span_at(code, 1, 24)),
+                    (Token::Char(')'), span_at(code, 1, 25)),
+                ],
+                span: span_at(code, 1, 1),
+            },
+        ])
+    );
+
+    let code = "#include_next <stdlib.h>";
+    assert_eq!(
+        parse(::lexer::tokenize_spanned(code).unwrap()),
+        Ok(vec![
+            Item::IncludeNext {
+                header: HeaderName::System(Cow::Borrowed("stdlib.h")),
+                span: span_at(code, 1, 1),
+            },
+        ])
+    );
+
+    let code = "#include <sys/types.h>\n";
+    assert_eq!(
+        parse(::lexer::tokenize_spanned(code).unwrap()),
+        Ok(vec![
+            Item::Include {
+                header: HeaderName::System(Cow::Borrowed("sys/types.h")),
+                span: span_at(code, 1, 1),
+            },
+            Item::Text {
+                tokens: vec![(Token::Newline { with_escape: false }, span_at(code, 1, 23))],
+                span: span_at(code, 1, 23),
+            },
+        ])
+    );
+    assert_eq!(
+        parse(::lexer::tokenize_spanned("#include <file\n").unwrap()).map_err(|err| err.kind),
+        Err(ErrorKind::ParsingError(ParseError::MissingParameter("include".to_string())))
+    );
+    assert_eq!(
+        parse(::lexer::tokenize_spanned("foo\n#include\n").unwrap()),
+        Err(Error::from(ParseError::MissingParameter("include".to_string())).or_at(Location {
+            file: String::new(),
+            line: 2,
+            column: 9,
+        }))
+    );
+
+    let code = "#include PLATFORM(gl) \n";
+    assert_eq!(
+        parse(::lexer::tokenize_spanned(code).unwrap()),
+        Ok(vec![
+            Item::Include {
+                header: HeaderName::Computed {
+                    tokens: vec![
+                        (Token::Word(Cow::Borrowed("PLATFORM")), span_at(code, 1, 10)),
+                        (Token::Char('('), span_at(code, 1, 18)),
+                        (Token::Word(Cow::Borrowed("gl")), span_at(code, 1, 19)),
+                        (Token::Char(')'), span_at(code, 1, 21)),
+                    ],
+                    span: span_at(code, 1, 1),
+                },
+                span: span_at(code, 1, 1),
+            },
+            Item::Text {
+                tokens: vec![(Token::Newline { with_escape: false }, span_at(code, 1, 23))],
+                span: span_at(code, 1, 23),
+            },
+        ])
     );
 }
 
@@ -305,8 +1049,98 @@ fn test_parse_include() {
 fn test_parse_undef() {
     let code = "#undef TEST";
     assert_eq!(
-        parse(::lexer::tokenize(code).unwrap()),
-        Ok(vec![Item::Undefine(Cow::Borrowed("TEST"))])
+        parse(::lexer::tokenize_spanned(code).unwrap()),
+        Ok(vec![
+            Item::Undefine {
+                name: Cow::Borrowed("TEST"),
+                span: span_at(code, 1, 1),
+            },
+        ])
+    );
+}
+
+#[test]
+fn test_parse_diagnostics() {
+    let code = "\
+#ifndef LIGHTS
+#error \"Unsupported light count\"  // LIGHTS
+#endif
+#warning  deprecated,\\
+  use v2";
+    assert_eq!(
+        parse(::lexer::tokenize_spanned(code).unwrap()),
+        Ok(vec![
+            Item::Conditional {
+                branches: vec![
+                    (
+                        Condition::NotDefined(Cow::Borrowed("LIGHTS")),
+                        vec![
+                            Item::Error {
+                                message: "\"Unsupported light count\"".to_string(),
+                                span: span_at(code, 2, 1),
+                            },
+                        ]
+                    ),
+                ],
+                else_branch: vec![],
+                span: span_at(code, 1, 1),
+            },
+            Item::Warning {
+                message: "deprecated, use v2".to_string(),
+                span: span_at(code, 4, 1),
+            },
+        ])
+    );
+}
+
+#[test]
+fn test_parse_line() {
+    let code = "foo\n#line 10 \"template.glsl\"\nbar";
+    assert_eq!(
+        parse(::lexer::tokenize_spanned(code).unwrap()),
+        Ok(vec![
+            Item::Text {
+                tokens: vec![
+                    (Token::Word(Cow::Borrowed("foo")), span_at(code, 1, 1)),
+                    (Token::Newline { with_escape: false }, span_at(code, 1, 4)),
+                ],
+                span: span_at(code, 1, 1),
+            },
+            Item::Line {
+                tokens: vec![
+                    (Token::Word(Cow::Borrowed("10")), span_at(code, 2, 7)),
+                    (Token::String(Cow::Borrowed("template.glsl")), span_at(code, 2, 10)),
+                ],
+                span: span_at(code, 2, 1),
+                end: span_at(code, 2, 25),
+            },
+            Item::Text {
+                tokens: vec![(Token::Word(Cow::Borrowed("bar")), span_at(code, 3, 1))],
+                span: span_at(code, 3, 1),
+            },
+        ])
+    );
+    assert_eq!(
+        parse(::lexer::tokenize_spanned("#line\n").unwrap()).map_err(|err| err.kind),
+        Err(ErrorKind::ParsingError(ParseError::MissingParameter("line".to_string())))
+    );
+}
+
+#[test]
+fn test_parse_pragma() {
+    let code = "#pragma  once \nfoo";
+    assert_eq!(
+        parse(::lexer::tokenize_spanned(code).unwrap()),
+        Ok(vec![
+            Item::Pragma {
+                tokens: vec![Token::Word(Cow::Borrowed("once"))],
+                span: span_at(code, 1, 1),
+            },
+            Item::Text {
+                tokens: vec![(Token::Word(Cow::Borrowed("foo")), span_at(code, 2, 1))],
+                span: span_at(code, 2, 1),
+            },
+        ])
     );
 }
 
@@ -314,60 +1148,257 @@ fn test_parse_undef() {
 fn test_parse_define() {
     let code = "#define TEST 0xFFFF // comment\nsome code";
     assert_eq!(
-        parse(::lexer::tokenize(code).unwrap()),
+        parse(::lexer::tokenize_spanned(code).unwrap()),
         Ok(vec![
-            Item::Define(
-                Cow::Borrowed("TEST"),
-                vec![Token::Word(Cow::Borrowed("0xFFFF"))]
-            ),
-            Item::Text(vec![
-                Token::Word(Cow::Borrowed("some")),
-                Token::Word(Cow::Borrowed("code")),
-            ]),
+            Item::Define {
+                name: Cow::Borrowed("TEST"),
+                params: None,
+                value: vec![Token::Word(Cow::Borrowed("0xFFFF"))],
+                span: span_at(code, 1, 1),
+            },
+            Item::Text {
+                tokens: vec![
+                    (Token::Word(Cow::Borrowed("some")), span_at(code, 2, 1)),
+                    (Token::Whitespace, span_at(code, 2, 5)),
+                    (Token::Word(Cow::Borrowed("code")), span_at(code, 2, 6)),
+                ],
+                span: span_at(code, 2, 1),
+            },
         ])
     );
 
     let code = "some code\n#define TEST 0xFFFF";
-    let token = ::lexer::tokenize(code).unwrap();
+    let token = ::lexer::tokenize_spanned(code).unwrap();
     println!("{:?}", token);
     assert_eq!(
         parse(token),
         Ok(vec![
-            Item::Text(vec![
-                Token::Word(Cow::Borrowed("some")),
-                Token::Word(Cow::Borrowed("code")),
-                Token::Newline { with_escape: false },
-            ]),
-            Item::Define(
-                Cow::Borrowed("TEST"),
-                vec![Token::Word(Cow::Borrowed("0xFFFF"))]
-            ),
+            Item::Text {
+                tokens: vec![
+                    (Token::Word(Cow::Borrowed("some")), span_at(code, 1, 1)),
+                    (Token::Whitespace, span_at(code, 1, 5)),
+                    (Token::Word(Cow::Borrowed("code")), span_at(code, 1, 6)),
+                    (Token::Newline { with_escape: false }, span_at(code, 1, 10)),
+                ],
+                span: span_at(code, 1, 1),
+            },
+            Item::Define {
+                name: Cow::Borrowed("TEST"),
+                params: None,
+                value: vec![Token::Word(Cow::Borrowed("0xFFFF"))],
+                span: span_at(code, 2, 1),
+            },
         ])
     );
 
     let code = "some code\n#define TEST 0xFFFF\\\n0xFFFE\nsome code";
-    let token = ::lexer::tokenize(code).unwrap();
+    let token = ::lexer::tokenize_spanned(code).unwrap();
     println!("{:?}", token);
     assert_eq!(
         parse(token),
         Ok(vec![
-            Item::Text(vec![
-                Token::Word(Cow::Borrowed("some")),
-                Token::Word(Cow::Borrowed("code")),
-                Token::Newline { with_escape: false },
-            ]),
-            Item::Define(
-                Cow::Borrowed("TEST"),
-                vec![
+            Item::Text {
+                tokens: vec![
+                    (Token::Word(Cow::Borrowed("some")), span_at(code, 1, 1)),
+                    (Token::Whitespace, span_at(code, 1, 5)),
+                    (Token::Word(Cow::Borrowed("code")), span_at(code, 1, 6)),
+                    (Token::Newline { with_escape: false }, span_at(code, 1, 10)),
+                ],
+                span: span_at(code, 1, 1),
+            },
+            Item::Define {
+                name: Cow::Borrowed("TEST"),
+                params: None,
+                value: vec![
                     Token::Word(Cow::Borrowed("0xFFFF")),
                     Token::Newline { with_escape: true },
                     Token::Word(Cow::Borrowed("0xFFFE")),
-                ]
-            ),
-            Item::Text(vec![
-                Token::Word(Cow::Borrowed("some")),
-                Token::Word(Cow::Borrowed("code")),
-            ]),
+                ],
+                span: span_at(code, 2, 1),
+            },
+            Item::Text {
+                tokens: vec![
+                    (Token::Word(Cow::Borrowed("some")), span_at(code, 4, 1)),
+                    (Token::Whitespace, span_at(code, 4, 5)),
+                    (Token::Word(Cow::Borrowed("code")), span_at(code, 4, 6)),
+                ],
+                span: span_at(code, 4, 1),
+            },
+        ])
+    );
+}
+
+#[test]
+fn test_parse_define_function() {
+    let code = "#define SATURATE( x ,y) clamp(x, 0.0, 1.0)";
+    assert_eq!(
+        parse(::lexer::tokenize_spanned(code).unwrap()),
+        Ok(vec![
+            Item::Define {
+                name: Cow::Borrowed("SATURATE"),
+                params: Some(vec![Cow::Borrowed("x"), Cow::Borrowed("y")]),
+                value: vec![
+                    Token::Word(Cow::Borrowed("clamp")),
+                    Token::Char('('),
+                    Token::Word(Cow::Borrowed("x")),
+                    Token::Char(','),
+                    Token::Whitespace,
+                    Token::Word(Cow::Borrowed("0.0")),
+                    Token::Char(','),
+                    Token::Whitespace,
+                    Token::Word(Cow::Borrowed("1.0")),
+                    Token::Char(')'),
+                ],
+                span: span_at(code, 1, 1),
+            },
+        ])
+    );
+
+    // Whitespace before the parenthesis makes it a part of the value of an object-like macro.
+    let code = "#define EMPTY() \n#define VALUE (x)";
+    assert_eq!(
+        parse(::lexer::tokenize_spanned(code).unwrap()),
+        Ok(vec![
+            Item::Define {
+                name: Cow::Borrowed("EMPTY"),
+                params: Some(vec![]),
+                value: vec![],
+                span: span_at(code, 1, 1),
+            },
+            Item::Define {
+                name: Cow::Borrowed("VALUE"),
+                params: None,
+                value: vec![Token::Char('('), Token::Word(Cow::Borrowed("x")), Token::Char(')')],
+                span: span_at(code, 2, 1),
+            },
+        ])
+    );
+
+    for code in &["#define F(x, x) x", "#define F(x,) x", "#define F(x y) x", "#define F(x"] {
+        assert_eq!(
+            parse(::lexer::tokenize_spanned(code).unwrap()).map_err(|err| err.kind),
+            Err(ErrorKind::ParsingError(ParseError::InvalidMacroParameters))
+        );
+    }
+}
+
+#[test]
+fn test_parse_define_operators() {
+    let code = "#define LABEL(x) # x\n#define HASH # y";
+    assert_eq!(
+        parse(::lexer::tokenize_spanned(code).unwrap()),
+        Ok(vec![
+            Item::Define {
+                name: Cow::Borrowed("LABEL"),
+                params: Some(vec![Cow::Borrowed("x")]),
+                value: vec![Token::Char('#'), Token::Whitespace, Token::Word(Cow::Borrowed("x"))],
+                span: span_at(code, 1, 1),
+            },
+            Item::Define {
+                name: Cow::Borrowed("HASH"),
+                params: None,
+                value: vec![Token::Char('#'), Token::Whitespace, Token::Word(Cow::Borrowed("y"))],
+                span: span_at(code, 2, 1),
+            },
         ])
     );
+
+    assert_eq!(
+        check_define("#define F(x) #y").map_err(|err| err.kind),
+        Err(ErrorKind::ParsingError(ParseError::StringizeWithoutParameter))
+    );
+    for code in &["#define F(x) ## x", "#define F(x) x ##", "#define F ## a"] {
+        assert_eq!(
+            check_define(code).map_err(|err| err.kind),
+            Err(ErrorKind::ParsingError(ParseError::MisplacedTokenPaste))
+        );
+    }
+}
+
+#[test]
+fn test_parse_define_variadic() {
+    let code = "#define LOG(fmt, ...) printf(fmt __VA_OPT__(,) __VA_ARGS__)";
+    match parse(::lexer::tokenize_spanned(code).unwrap()) {
+        Ok(ref items) => {
+            match items[..] {
+                [Item::Define { ref params, .. }] => {
+                    assert_eq!(*params, Some(vec![Cow::Borrowed("fmt"), Cow::Borrowed("...")]));
+                }
+                _ => panic!("expected a define, got {:?}", items),
+            }
+        }
+        Err(e) => panic!("{:?}", e),
+    }
+
+    for code in &["#define F(..., x) x", "#define F(__VA_ARGS__) x", "#define F(1) x"] {
+        assert_eq!(
+            parse(::lexer::tokenize_spanned(code).unwrap()).map_err(|err| err.kind),
+            Err(ErrorKind::ParsingError(ParseError::InvalidMacroParameters))
+        );
+    }
+    for code in &["#define F(x) __VA_ARGS__", "#define F __VA_OPT__(x)"] {
+        assert_eq!(
+            check_define(code).map_err(|err| err.kind),
+            Err(ErrorKind::ParsingError(ParseError::UnexpectedVariadicIdentifier))
+        );
+    }
+    for code in &[
+        "#define F(...) __VA_OPT__",
+        "#define F(...) __VA_OPT__(x",
+        "#define F(...) a __VA_OPT__(## b)",
+        "#define F(...) __VA_OPT__(b ##) a",
+    ] {
+        assert_eq!(
+            check_define(code).map_err(|err| err.kind),
+            Err(ErrorKind::ParsingError(ParseError::InvalidVaOpt))
+        );
+    }
+}
+
+#[test]
+fn test_parse_recovering() {
+    let code = "\
+#ifdef A
+foo
+#frobnicate x
+#define
+bar
+#else
+#elif 1
+baz
+";
+    let (items, errors) = parse_recovering(::lexer::tokenize_spanned(code).unwrap());
+    let errors = errors
+        .into_iter()
+        .map(|err| {
+            let location = err.context.location.map(|location| (location.line, location.column));
+            (err.kind, location)
+        })
+        .collect::<Vec<_>>();
+    let error = |err, line, column| (ErrorKind::ParsingError(err), Some((line, column)));
+    assert_eq!(errors, vec![
+        error(ParseError::UnrecognizedPreprocessor("frobnicate".to_string()), 3, 1),
+        error(ParseError::MissingParameter("define".to_string()), 4, 8),
+        error(ParseError::ElifAfterElse("elif".to_string()), 7, 1),
+        error(ParseError::ElseWithoutEndif, 6, 1),
+    ]);
+    match items[..] {
+        [Item::Conditional { ref branches, ref else_branch, .. }] => {
+            assert_eq!(branches.len(), 1);
+            assert_eq!(branches[0].1.len(), 2);
+            assert_eq!(else_branch.len(), 1);
+        }
+        _ => panic!("expected a conditional, got {:?}", items),
+    }
+
+    assert_eq!(
+        parse_recovering(::lexer::tokenize_spanned("#if\nfoo\n#endif\n#endif\n").unwrap()).1
+            .into_iter()
+            .map(|err| err.kind)
+            .collect::<Vec<_>>(),
+        vec![
+            ErrorKind::ParsingError(ParseError::MissingParameter("if".to_string())),
+            ErrorKind::ParsingError(ParseError::UnexpectedPreprocessor("endif".to_string())),
+        ]
+    );
 }
@@ -1,151 +1,1241 @@
 
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::ops::Deref;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use expression;
 use lexer;
+use loader::FileLoader;
+use macros::{Expander, Macro};
 use parser;
-use parser::Item;
-use lexer::Token;
+use parser::{Condition, HeaderName, Item};
+use lexer::{Span, Token};
 use error::*;
 
-static FORMAT_MAX_DEPTH: i32 = 100;
-
+/// A macro defined before processing, like `-D` of a compiler.
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub struct Define<'a, 'b> {
     name: &'a str,
     value: Option<&'b str>,
 }
 
 impl<'a, 'b> Define<'a, 'b> {
+    /// Defines `name` as `value`, which is tokenized as code.
     pub fn new(name: &'a str, value: &'b str) -> Define<'a, 'b> {
         Define {
             name,
             value: Some(value),
         }
     }
+
+    /// Defines `name` without a value, like `#define FLAG`.
+    pub fn flag(name: &'a str) -> Define<'a, 'b> {
+        Define { name, value: None }
+    }
 }
 
-pub fn process<F>(code: &str, defines: &[Define], file_loader: F) -> Result<String>
-where
-    F: Fn(&str) -> Option<String>,
-{
-    let mut defines = defines
-        .into_iter()
-        .map(|n| (n.name.to_string(), n.value.map(|a| a.to_string())))
-        .collect::<HashMap<_, _>>();
-    let tokens = lexer::tokenize(code)?;
-    let parsed = parser::parse(tokens)?;
-    process_mut_defines(parsed, &mut defines, &file_loader)
-}
-
-pub fn process_mut_defines<'a, F>(
-    parsed: Vec<Item>,
-    defines: &mut HashMap<String, Option<String>>,
-    file_loader: &F,
-) -> Result<String>
-where
-    F: Fn(&str) -> Option<String>,
-{
-    let mut result = String::new();
+/// The name of the processed code, unless it's given to `Processor::process_file`.
+const INPUT_NAME: &str = "<input>";
 
-    for item in parsed {
-        match item {
-            Item::Text(tokens) => {
-                format_tokens_to_string(&mut result, &tokens[..], FORMAT_MAX_DEPTH, defines)?;
-            }
-            Item::Undefine(s) => {
-                defines.remove(s.deref());
-            }
-            Item::Define(symbol, value) => {
-                let mut val = String::new();
-                format_tokens_to_string(&mut val, &value[..], FORMAT_MAX_DEPTH, defines)?;
-                defines.insert(symbol.to_string(), Some(val));
-            }
-            Item::Include(f) => {
-                match file_loader(f.deref()) {
-                    Some(file_contents) => {
-                        let tokens = lexer::tokenize(file_contents.as_str())?;
-                        let parsed = parser::parse(tokens)?;
-                        let processed = process_mut_defines(parsed, defines, file_loader)?;
-                        result.push_str(processed.as_str());
-                    }
-                    None => Err(Error::CantOpenFile)?,
+/// How deeply `#include` can nest, like in GCC, so that a file including itself fails instead
+/// of overflowing the stack.
+const MAX_INCLUDE_DEPTH: usize = 200;
+
+/// The processed text, and the diagnostics of `#warning` directives.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ProcessOutput {
+    pub text: String,
+    pub warnings: Vec<Warning>,
+    /// The errors recovered from with `Processor::recover_errors`, in which case the text is
+    /// incomplete. Always empty otherwise, as the first error fails processing.
+    pub errors: Vec<Error>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Warning {
+    pub message: String,
+    pub location: Location,
+}
+
+// Mutable state carried through the processed files.
+struct State {
+    defines: HashMap<String, Macro>,
+    warnings: Vec<Warning>,
+    errors: Vec<Error>,
+    counter: Cell<usize>,
+    date: String,
+    time: String,
+    // The name of the processed code, for `__BASE_FILE__`.
+    base_file: String,
+    // The canonical ids of the files marked with `#pragma once`.
+    once: HashSet<String>,
+    // The include guard macros of files by their canonical ids.
+    guards: HashMap<String, String>,
+    // The files found for `#include`, so that a file skipped by its guard isn't searched again.
+    found_includes: HashMap<IncludeSearch, FoundInclude>,
+    // The number of files lexed so far, which gives the id of the next one in spans.
+    files: usize,
+    // The definitions saved by `#pragma push_macro`, `None` when the macro wasn't defined.
+    pushed_macros: HashMap<String, Vec<Option<Macro>>>,
+}
+
+// The header name as written, the directory of the including file, whether it's
+// `#include_next`, and the search path index which that continues after.
+type IncludeSearch = (String, String, bool, Option<usize>);
+
+// The path, the search path index and the canonical id of a found file.
+type FoundInclude = (String, Option<usize>, String);
+
+/// The parameters of `#embed`, with the token parameters formatted as text.
+#[derive(Default)]
+struct EmbedParameters {
+    limit: Option<usize>,
+    prefix: String,
+    suffix: String,
+    if_empty: Option<String>,
+}
+
+/// Where a built-in macro is expanded.
+#[derive(Debug, PartialEq, Clone)]
+pub struct BuiltinContext<'a> {
+    pub file: &'a str,
+    pub line: usize,
+    pub include_level: usize,
+}
+
+/// What to do with a `#pragma` directive, decided by a handler registered with
+/// `Processor::pragma_handler`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum PragmaAction {
+    /// Passes the directive through to the output as it is.
+    Keep,
+    /// Removes the directive from the output.
+    Consume,
+    /// Replaces the directive line in the output with the text.
+    Rewrite(String),
+    /// Fails processing with the message.
+    Reject(String),
+}
+
+type PragmaHandler = Rc<dyn Fn(&str) -> PragmaAction>;
+
+/// A macro which is always defined, and gets its value when it's expanded.
+#[derive(Clone)]
+enum Builtin {
+    File,
+    Line,
+    Counter,
+    Date,
+    Time,
+    IncludeLevel,
+    BaseFile,
+    Custom(Rc<dyn Fn(&BuiltinContext) -> String>),
+}
+
+/// The name and the line numbering of a file being processed, which `#line` can change.
+struct SourceFile {
+    name: String,
+    // The path the file was loaded from.
+    path: String,
+    // The canonical id of an included file.
+    id: Option<String>,
+    // The index of the search path the file was found from, where `#include_next` continues
+    // the search.
+    search_index: Option<usize>,
+    // The physical line which is reported as `logical_line`. The lines after it are numbered
+    // consecutively.
+    physical_line: usize,
+    logical_line: usize,
+    include_level: usize,
+}
+
+impl SourceFile {
+    fn input(name: &str) -> SourceFile {
+        SourceFile {
+            name: name.to_string(),
+            path: name.to_string(),
+            id: None,
+            search_index: None,
+            physical_line: 1,
+            logical_line: 1,
+            include_level: 0,
+        }
+    }
+
+    fn included(
+        path: String,
+        id: String,
+        search_index: Option<usize>,
+        include_level: usize,
+    ) -> SourceFile {
+        SourceFile {
+            name: path.clone(),
+            path,
+            id: Some(id),
+            search_index,
+            physical_line: 1,
+            logical_line: 1,
+            include_level,
+        }
+    }
+
+    /// The directory of the file, empty for a name without one.
+    fn directory(&self) -> &str {
+        match self.path.rfind('/') {
+            Some(index) => &self.path[..index],
+            None => "",
+        }
+    }
+
+    fn line(&self, physical_line: usize) -> usize {
+        self.logical_line + physical_line - self.physical_line
+    }
+
+    fn location(&self, span: Span) -> Location {
+        Location {
+            file: self.name.clone(),
+            line: self.line(span.line),
+            column: span.column,
+        }
+    }
+}
+
+/// Preprocessor with configurable behavior. The free function [`process`] uses the defaults.
+#[derive(Clone)]
+pub struct Processor {
+    gnu_comma_paste: bool,
+    recover_errors: bool,
+    builtins: HashMap<String, Builtin>,
+    pragma_handlers: HashMap<String, PragmaHandler>,
+    date: Option<String>,
+    time: Option<String>,
+    quote_paths: Vec<String>,
+    include_paths: Vec<String>,
+    system_paths: Vec<String>,
+}
+
+impl Default for Processor {
+    fn default() -> Processor {
+        let builtins = vec![
+            ("__FILE__", Builtin::File),
+            ("__LINE__", Builtin::Line),
+            ("__COUNTER__", Builtin::Counter),
+            ("__DATE__", Builtin::Date),
+            ("__TIME__", Builtin::Time),
+            ("__INCLUDE_LEVEL__", Builtin::IncludeLevel),
+            ("__BASE_FILE__", Builtin::BaseFile),
+        ];
+        Processor {
+            gnu_comma_paste: false,
+            recover_errors: false,
+            builtins: builtins
+                .into_iter()
+                .map(|(name, builtin)| (name.to_string(), builtin))
+                .collect(),
+            pragma_handlers: HashMap::new(),
+            date: None,
+            time: None,
+            quote_paths: vec![],
+            include_paths: vec![],
+            system_paths: vec![],
+        }
+    }
+}
+
+impl fmt::Debug for Processor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut builtins = self.builtins.keys().collect::<Vec<_>>();
+        builtins.sort();
+        let mut pragma_handlers = self.pragma_handlers.keys().collect::<Vec<_>>();
+        pragma_handlers.sort();
+        f.debug_struct("Processor")
+            .field("gnu_comma_paste", &self.gnu_comma_paste)
+            .field("recover_errors", &self.recover_errors)
+            .field("builtins", &builtins)
+            .field("pragma_handlers", &pragma_handlers)
+            .field("date", &self.date)
+            .field("time", &self.time)
+            .field("quote_paths", &self.quote_paths)
+            .field("include_paths", &self.include_paths)
+            .field("system_paths", &self.system_paths)
+            .finish()
+    }
+}
+
+impl Processor {
+    pub fn new() -> Processor {
+        Processor::default()
+    }
+
+    /// Enables the GNU extension where `, ## __VA_ARGS__` drops the comma when the variable
+    /// arguments are empty, instead of pasting the comma to the first argument.
+    pub fn gnu_comma_paste(mut self, enabled: bool) -> Processor {
+        self.gnu_comma_paste = enabled;
+        self
+    }
+
+    /// Collects all errors in one pass instead of failing at the first one, e.g. for editors.
+    /// The lexer and the parser skip to the next line after an error, and a failed item (a
+    /// directive, or text up to the next directive) is left out of the output. The errors are
+    /// given in `ProcessOutput::errors`.
+    pub fn recover_errors(mut self, enabled: bool) -> Processor {
+        self.recover_errors = enabled;
+        self
+    }
+
+    /// Registers a built-in macro, which gets its value from `value` each time it's expanded.
+    /// The value is tokenized as code, so a string has to be quoted. Replaces the predefined
+    /// built-in of the same name.
+    pub fn builtin<F>(mut self, name: &str, value: F) -> Processor
+    where
+        F: Fn(&BuiltinContext) -> String + 'static,
+    {
+        self.builtins.insert(name.to_string(), Builtin::Custom(Rc::new(value)));
+        self
+    }
+
+    /// Registers a handler for `#pragma name ...`, which gets the rest of the directive as it
+    /// is. Unknown pragmas are passed through to the output, and the handler replaces the
+    /// interpretation of the known ones, i.e. `once`, `message`, `push_macro` and `pop_macro`.
+    pub fn pragma_handler<F>(mut self, name: &str, handler: F) -> Processor
+    where
+        F: Fn(&str) -> PragmaAction + 'static,
+    {
+        self.pragma_handlers.insert(name.to_string(), Rc::new(handler));
+        self
+    }
+
+    /// Pins the value of `__DATE__`, e.g. `"Jan  1 2024"`, instead of using the current date
+    /// (in UTC).
+    pub fn date(mut self, date: &str) -> Processor {
+        self.date = Some(date.to_string());
+        self
+    }
+
+    /// Pins the value of `__TIME__`, e.g. `"12:00:00"`, instead of using the current time (in
+    /// UTC).
+    pub fn time(mut self, time: &str) -> Processor {
+        self.time = Some(time.to_string());
+        self
+    }
+
+    /// Adds a directory searched for `#include "file"`, after the directory of the including
+    /// file, like `-iquote`.
+    pub fn quote_path(mut self, path: &str) -> Processor {
+        self.quote_paths.push(path.to_string());
+        self
+    }
+
+    /// Adds a directory searched for both `#include "file"` and `#include <file>`, like `-I`.
+    pub fn include_path(mut self, path: &str) -> Processor {
+        self.include_paths.push(path.to_string());
+        self
+    }
+
+    /// Adds a directory searched for both `#include "file"` and `#include <file>` after the
+    /// include paths, like `-isystem`.
+    pub fn system_path(mut self, path: &str) -> Processor {
+        self.system_paths.push(path.to_string());
+        self
+    }
+
+    pub fn process<F>(
+        &self,
+        code: &str,
+        defines: &[Define],
+        file_loader: F,
+    ) -> Result<ProcessOutput>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        self.process_with_loader(code, defines, &file_loader)
+    }
+
+    /// Like `process`, but loads the included files with a [`FileLoader`], which can e.g. give
+    /// the canonical ids of the files.
+    pub fn process_with_loader<L>(
+        &self,
+        code: &str,
+        defines: &[Define],
+        file_loader: &L,
+    ) -> Result<ProcessOutput>
+    where
+        L: FileLoader,
+    {
+        self.process_file(INPUT_NAME, code, defines, file_loader)
+    }
+
+    /// Like `process_with_loader`, but names the processed code by its path, e.g.
+    /// `shaders/pbr.glsl`. The name is used for `__FILE__`, `__BASE_FILE__` and the locations of
+    /// errors, and quoted includes are searched first from its directory.
+    pub fn process_file<L>(
+        &self,
+        name: &str,
+        code: &str,
+        defines: &[Define],
+        file_loader: &L,
+    ) -> Result<ProcessOutput>
+    where
+        L: FileLoader,
+    {
+        let mut macros = HashMap::new();
+        for define in defines {
+            let body = match define.value {
+                Some(value) => {
+                    lexer::tokenize(value)?
+                        .into_iter()
+                        .map(Token::into_owned)
+                        .collect()
                 }
+                None => vec![],
+            };
+            macros.insert(define.name.to_string(), Macro::new(None, body));
+        }
+        let (date, time) = format_date_time(SystemTime::now());
+        let mut state = State {
+            defines: macros,
+            warnings: vec![],
+            errors: vec![],
+            counter: Cell::new(0),
+            date: self.date.clone().unwrap_or(date),
+            time: self.time.clone().unwrap_or(time),
+            base_file: name.to_string(),
+            once: HashSet::new(),
+            guards: HashMap::new(),
+            found_includes: HashMap::new(),
+            files: 0,
+            pushed_macros: HashMap::new(),
+        };
+        let mut file = SourceFile::input(name);
+        let parsed = self.parse(code, file.name.as_str(), &mut state)?;
+        let text = self.process_items(parsed, &mut file, &mut state, file_loader)?;
+        Ok(ProcessOutput {
+            text,
+            warnings: state.warnings,
+            errors: state.errors,
+        })
+    }
+
+    /// Lexes and parses the code of a file, collecting the errors if recovering from them.
+    fn parse<'a>(&self, code: &'a str, name: &str, state: &mut State) -> Result<Vec<Item<'a>>> {
+        let id = state.files;
+        state.files += 1;
+        if !self.recover_errors {
+            return lexer::tokenize_file(code, id)
+                .and_then(parser::parse)
+                .map_err(|err| err.in_file(name));
+        }
+        let (tokens, lexing_errors) = lexer::tokenize_recovering(code, id);
+        let (items, parsing_errors) = parser::parse_recovering(tokens);
+        let errors = lexing_errors.into_iter().chain(parsing_errors);
+        state.errors.extend(errors.map(|err| err.in_file(name)));
+        Ok(items)
+    }
+
+    /// Fails with the error, or collects it if recovering from errors.
+    fn recover(&self, err: Error, state: &mut State) -> Result<()> {
+        if !self.recover_errors {
+            return Err(err);
+        }
+        state.errors.push(err);
+        Ok(())
+    }
+
+    fn process_items<L>(
+        &self,
+        parsed: Vec<Item>,
+        file: &mut SourceFile,
+        state: &mut State,
+        file_loader: &L,
+    ) -> Result<String>
+    where
+        L: FileLoader,
+    {
+        let mut result = String::new();
+
+        for item in parsed {
+            let span = item.span();
+            if let Err(err) = self.process_item(item, &mut result, file, state, file_loader) {
+                self.recover(err.or_at(file.location(span)), state)?;
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn process_item<L>(
+        &self,
+        item: Item,
+        result: &mut String,
+        file: &mut SourceFile,
+        state: &mut State,
+        file_loader: &L,
+    ) -> Result<()>
+    where
+        L: FileLoader,
+    {
+        match item {
+            Item::Text { tokens, .. } => {
+                let expanded = self.expander(state, file).expand(&tokens[..])?;
+                format_tokens_to_string(result, &expanded[..]);
+            }
+            Item::Undefine { name, .. } => {
+                state.defines.remove(name.deref());
+            }
+            Item::Define {
+                name,
+                params,
+                value,
+                span,
+            } => {
+                parser::check_macro_body(params.as_ref().map(|p| &p[..]), &value[..])?;
+                let mut definition = Macro::new(params, value);
+                definition.location = Some(file.location(span));
+                state.defines.insert(name.to_string(), definition);
+            }
+            Item::Include { header, span } => {
+                let processed = self.include(header, false, span, file, state, file_loader)?;
+                result.push_str(processed.as_str());
+            }
+            Item::IncludeNext { header, span } => {
+                let processed = self.include(header, true, span, file, state, file_loader)?;
+                result.push_str(processed.as_str());
+            }
+            Item::Embed { tokens, span } => {
+                let (bytes, parameters) = self
+                    .embed(&tokens[..], file, span, state, file_loader)?
+                    .ok_or(ErrorKind::CantOpenFile)?;
+                result.push_str(format_embed(&bytes[..], parameters).as_str());
+                result.push('\n');
             }
             Item::Conditional {
-                define_name,
-                defined,
-                not_defined,
+                branches,
+                else_branch,
+                ..
             } => {
-                let processed = if defines.contains_key(define_name.deref()) {
-                    process_mut_defines(defined, defines, file_loader)?
+                let mut taken = None;
+                for (condition, items) in branches {
+                    if self.evaluate_condition(&condition, state, file, file_loader)? {
+                        taken = Some(items);
+                        break;
+                    }
+                }
+                let processed =
+                    self.process_items(taken.unwrap_or(else_branch), file, state, file_loader)?;
+                result.push_str(processed.as_str());
+            }
+            Item::Error { message, .. } => Err(ErrorKind::ErrorDirective(message))?,
+            Item::Warning { message, span } => {
+                state.warnings.push(Warning {
+                    message,
+                    location: file.location(span),
+                });
+            }
+            Item::Line { tokens, end, .. } => {
+                let expanded = self.expander(state, file).expand(&tokens[..])?;
+                let (number, name) = parse_line_arguments(&expanded[..])?;
+                file.physical_line = end.line + 1;
+                file.logical_line = number;
+                if let Some(name) = name {
+                    file.name = name;
+                }
+            }
+            Item::Pragma { tokens, span } => {
+                let name = match tokens.first() {
+                    Some(Token::Word(name)) => name.deref(),
+                    _ => "",
+                };
+                let action = match self.pragma_handlers.get(name) {
+                    Some(handler) => {
+                        let arguments = join_tokens(tokens.get(1..).unwrap_or(&[]));
+                        handler(arguments.trim())
+                    }
+                    None => self.interpret_pragma(name, &tokens[..], file, span, state)?,
+                };
+                match action {
+                    PragmaAction::Keep => {
+                        result.push_str("#pragma ");
+                        result.push_str(join_tokens(&tokens[..]).as_str());
+                        result.push('\n');
+                    }
+                    PragmaAction::Consume => {}
+                    PragmaAction::Rewrite(text) => {
+                        result.push_str(text.as_str());
+                        result.push('\n');
+                    }
+                    PragmaAction::Reject(message) => Err(ErrorKind::RejectedPragma(message))?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Processes an included file, which gives nothing if it's already been included and marked
+    /// with `#pragma once` or an include guard. Errors in the file get the `#include` at `span`
+    /// added to their include stack.
+    fn include<L>(
+        &self,
+        header: HeaderName,
+        next: bool,
+        span: Span,
+        file: &SourceFile,
+        state: &mut State,
+        file_loader: &L,
+    ) -> Result<String>
+    where
+        L: FileLoader,
+    {
+        let header = match header {
+            HeaderName::Computed { tokens, .. } => {
+                let expanded = self.expander(state, file).expand(&tokens[..])?;
+                parse_computed_header_name(&expanded[..])?
+            }
+            header => header,
+        };
+        let name = match header {
+            HeaderName::Quoted(ref name) => format!("\"{}\"", name),
+            HeaderName::System(ref name) => format!("<{}>", name),
+            HeaderName::Computed { .. } => unreachable!(),
+        };
+        let directory = file.directory().to_string();
+        let search = (name, directory, next, file.search_index.filter(|_| next));
+        let (path, search_index, id) = match state.found_includes.get(&search) {
+            Some(found) => found.clone(),
+            None => {
+                let (path, search_index) = self
+                    .find_include(&header, file, next, file_loader)
+                    .ok_or(ErrorKind::CantOpenFile)?;
+                let id = file_loader.canonical_id(path.as_str());
+                let found = (path, search_index, id);
+                state.found_includes.insert(search, found.clone());
+                found
+            }
+        };
+        let guarded = state
+            .guards
+            .get(&id)
+            .is_some_and(|guard| state.defines.contains_key(guard));
+        if guarded || state.once.contains(&id) {
+            return Ok(String::new());
+        }
+        let level = file.include_level + 1;
+        if level > MAX_INCLUDE_DEPTH {
+            Err(ErrorKind::IncludeTooDeep(MAX_INCLUDE_DEPTH))?
+        }
+        let bytes = file_loader.load_bytes(path.as_str()).ok_or(ErrorKind::CantOpenFile)?;
+        let included_from = file.location(span);
+        // The errors recovered from in the file get the include stack as well.
+        let first_error = state.errors.len();
+        let (file_contents, err) = lexer::decode(bytes);
+        if let Some(err) = err {
+            self.recover(err.in_file(path.as_str()), state)
+                .map_err(|err| err.included_from(included_from.clone()))?;
+        }
+        let parsed = self
+            .parse(file_contents.as_str(), path.as_str(), state)
+            .map_err(|err| err.included_from(included_from.clone()))?;
+        if let Some(guard) = include_guard(&parsed[..]) {
+            state.guards.insert(id.clone(), guard.to_string());
+        }
+        let mut included = SourceFile::included(path, id, search_index, level);
+        let processed = self
+            .process_items(parsed, &mut included, state, file_loader)
+            .map_err(|err| err.included_from(included_from.clone()));
+        for err in &mut state.errors[first_error..] {
+            err.context.include_stack.push(included_from.clone());
+        }
+        processed
+    }
+
+    /// Loads the resource of `#embed` or `__has_embed`, limited by the `limit` parameter. Gives
+    /// `None` if the resource isn't found. A macro expanded header name is located at `span`.
+    fn embed<L>(
+        &self,
+        tokens: &[(Token, Span)],
+        file: &SourceFile,
+        span: Span,
+        state: &State,
+        file_loader: &L,
+    ) -> Result<Option<(Vec<u8>, EmbedParameters)>>
+    where
+        L: FileLoader,
+    {
+        let expanded;
+        let (header, tokens) = match split_header_name(tokens) {
+            Some(split) => split,
+            None => {
+                let tokens = self.expander(state, file).expand(tokens)?;
+                expanded = tokens.into_iter().map(|token| (token, span)).collect::<Vec<_>>();
+                split_header_name(&expanded[..]).ok_or(ParseError::InvalidHeaderName)?
+            }
+        };
+        let mut parameters = EmbedParameters::default();
+        let mut i = tokens.iter().filter(|(token, _)| *token != Token::Whitespace);
+        while let Some((token, _)) = i.next() {
+            let name = match *token {
+                Token::Word(ref name) => {
+                    let standard = name.strip_prefix("__").and_then(|name| name.strip_suffix("__"));
+                    standard.unwrap_or(name)
+                }
+                _ => Err(ParseError::InvalidEmbedParameter(token.formatted_str().into_owned()))?,
+            };
+            let invalid = || ParseError::InvalidEmbedParameter(name.to_string());
+            if i.next().map(|(token, _)| token) != Some(&Token::Char('(')) {
+                Err(invalid())?
+            }
+            let mut arguments = vec![];
+            let mut depth = 0;
+            loop {
+                match i.next() {
+                    Some((Token::Char(')'), _)) if depth == 0 => break,
+                    Some(token) => {
+                        match token.0 {
+                            Token::Char('(') => depth += 1,
+                            Token::Char(')') => depth -= 1,
+                            _ => (),
+                        }
+                        arguments.push(token.clone());
+                    }
+                    None => Err(invalid())?,
+                }
+            }
+            let mut text = String::new();
+            format_tokens_to_string(&mut text, &strip_spans(&arguments[..]));
+            match name {
+                "limit" => {
+                    let expanded = self.expander(state, file).expand(&arguments[..])?;
+                    let limit = match expression::evaluate(&expanded[..])? {
+                        expression::Value::Signed(value) if value >= 0 => value as usize,
+                        expression::Value::Unsigned(value) => value as usize,
+                        _ => Err(invalid())?,
+                    };
+                    parameters.limit = Some(limit);
+                }
+                "prefix" => parameters.prefix = text,
+                "suffix" => parameters.suffix = text,
+                "if_empty" => parameters.if_empty = Some(text),
+                _ => Err(invalid())?,
+            }
+        }
+        let path = match self.find_include(&header, file, false, file_loader) {
+            Some((path, _)) => path,
+            None => return Ok(None),
+        };
+        Ok(file_loader.load_bytes(path.as_str()).map(|mut bytes| {
+            bytes.truncate(parameters.limit.unwrap_or(bytes.len()));
+            (bytes, parameters)
+        }))
+    }
+
+    /// Finds the path of an included file, and the index of the search path it was found from.
+    /// The last candidate is used without checking that it exists, so that failing to load it is
+    /// reported. Without search paths, the name of `#include <file>` is used as it is. With
+    /// `next`, the search continues after the search path the current file was found from, or at
+    /// the search paths for a file found in the directory of its includer, and gives `None` when
+    /// there are no more search paths.
+    fn find_include<L>(
+        &self,
+        header: &HeaderName,
+        file: &SourceFile,
+        next: bool,
+        file_loader: &L,
+    ) -> Option<(String, Option<usize>)>
+    where
+        L: FileLoader,
+    {
+        let (name, first, current_directory) = match *header {
+            HeaderName::Quoted(ref name) => (name, 0, Some(file.directory())),
+            HeaderName::System(ref name) => (name, self.quote_paths.len(), None),
+            HeaderName::Computed { .. } => unreachable!(),
+        };
+        let (first, current_directory, continued) = match file.search_index {
+            Some(index) if next => (index + 1, None, true),
+            // Searching the directory again would find the current file.
+            None if next && file.id.is_some() => (first, None, true),
+            _ => (first, current_directory, false),
+        };
+        let search_paths = current_directory
+            .map(|path| (None, path))
+            .into_iter()
+            .chain(
+                self.quote_paths
+                    .iter()
+                    .chain(self.include_paths.iter())
+                    .chain(self.system_paths.iter())
+                    .enumerate()
+                    .skip(first)
+                    .map(|(index, path)| (Some(index), path.as_str())),
+            )
+            .collect::<Vec<_>>();
+        if name.starts_with('/') || (search_paths.is_empty() && !continued) {
+            return Some((normalize_path(name), None));
+        }
+        let mut candidates = search_paths
+            .iter()
+            .map(|&(index, path)| (join_path(path, name), index))
+            .collect::<Vec<_>>();
+        let last = candidates.pop()?;
+        candidates
+            .into_iter()
+            .find(|candidate| file_loader.exists(candidate.0.as_str()))
+            .or(Some(last))
+    }
+
+    /// Interprets the known pragmas, and keeps the others.
+    fn interpret_pragma(
+        &self,
+        name: &str,
+        tokens: &[Token],
+        file: &SourceFile,
+        span: Span,
+        state: &mut State,
+    ) -> Result<PragmaAction> {
+        match name {
+            "once" => {
+                if let Some(ref id) = file.id {
+                    state.once.insert(id.clone());
+                }
+            }
+            "message" => {
+                // The tokens of a pragma are located at the directive.
+                let tokens = tokens[1..].iter().map(|token| (token.clone(), span));
+                let expanded = self.expander(state, file).expand(&tokens.collect::<Vec<_>>())?;
+                let strings = expanded
+                    .iter()
+                    .filter_map(|token| match *token {
+                        Token::String(ref value) => Some(unescape(value)),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                let message = if strings.is_empty() {
+                    join_tokens(&expanded[..]).trim().to_string()
                 } else {
-                    process_mut_defines(not_defined, defines, file_loader)?
+                    strings.concat()
                 };
-                result.push_str(processed.as_str());
+                state.warnings.push(Warning {
+                    message,
+                    location: file.location(span),
+                });
+            }
+            "push_macro" => {
+                let name = pragma_macro_name(name, &tokens[1..])?;
+                let definition = state.defines.get(name.as_str()).cloned();
+                state.pushed_macros.entry(name).or_default().push(definition);
+            }
+            "pop_macro" => {
+                let name = pragma_macro_name(name, &tokens[1..])?;
+                // Popping without a pushed definition leaves the macro as it is.
+                let pushed = state.pushed_macros.get_mut(&name).and_then(|stack| stack.pop());
+                match pushed {
+                    Some(Some(definition)) => {
+                        state.defines.insert(name, definition);
+                    }
+                    Some(None) => {
+                        state.defines.remove(&name);
+                    }
+                    None => {}
+                }
             }
+            _ => return Ok(PragmaAction::Keep),
         }
+        Ok(PragmaAction::Consume)
     }
 
-    Ok(result)
+    fn evaluate_condition<L>(
+        &self,
+        condition: &Condition,
+        state: &State,
+        file: &SourceFile,
+        file_loader: &L,
+    ) -> Result<bool>
+    where
+        L: FileLoader,
+    {
+        let is_defined = |name: &str| {
+            state.defines.contains_key(name)
+                || self.builtins.contains_key(name)
+                || name == "__has_include"
+                || name == "__has_include_next"
+                || name == "__has_embed"
+        };
+        match *condition {
+            Condition::Defined(ref name) => Ok(is_defined(name)),
+            Condition::NotDefined(ref name) => Ok(!is_defined(name)),
+            Condition::Expression { ref tokens, span } => {
+                let has_operator = |operator: &str, operand: &[(Token, Span)]| {
+                    if operator == "__has_embed" {
+                        return match self.embed(operand, file, span, state, file_loader) {
+                            Ok(Some((bytes, _))) => Ok(if bytes.is_empty() { 2 } else { 1 }),
+                            Ok(None) |
+                            Err(Error {
+                                kind: ErrorKind::ParsingError(ParseError::InvalidEmbedParameter(_)),
+                                ..
+                            }) => Ok(0),
+                            Err(err) => Err(err),
+                        };
+                    }
+                    let header = match parse_computed_header_name(&strip_spans(operand)) {
+                        Ok(header) => header,
+                        Err(_) => {
+                            let expanded = self.expander(state, file).expand(operand)?;
+                            parse_computed_header_name(&expanded[..])?
+                        }
+                    };
+                    let next = operator == "__has_include_next";
+                    Ok(self
+                        .find_include(&header, file, next, file_loader)
+                        .is_some_and(|(path, _)| file_loader.exists(path.as_str())) as usize)
+                };
+                let tokens = replace_defined(&tokens[..], is_defined, has_operator)?;
+                let expanded = self.expander(state, file).expand(&tokens[..])?;
+                Ok(expression::evaluate(&expanded[..])?.is_true())
+            }
+        }
+    }
+
+    /// Creates an expander for tokens of the file.
+    fn expander<'d>(&'d self, state: &'d State, file: &'d SourceFile) -> Expander<'d> {
+        Expander {
+            defines: &state.defines,
+            gnu_comma_paste: self.gnu_comma_paste,
+            builtins: Box::new(move |name, span| {
+                let line = file.line(span.line);
+                let builtin = match self.builtins.get(name) {
+                    Some(builtin) => builtin,
+                    None => return Ok(None),
+                };
+                let token = match *builtin {
+                    Builtin::File => string_literal(file.name.as_str()),
+                    Builtin::Line => number_literal(line),
+                    Builtin::Counter => {
+                        let counter = state.counter.get();
+                        state.counter.set(counter + 1);
+                        number_literal(counter)
+                    }
+                    Builtin::Date => string_literal(state.date.as_str()),
+                    Builtin::Time => string_literal(state.time.as_str()),
+                    Builtin::IncludeLevel => number_literal(file.include_level),
+                    Builtin::BaseFile => string_literal(state.base_file.as_str()),
+                    Builtin::Custom(ref value) => {
+                        let context = BuiltinContext {
+                            file: file.name.as_str(),
+                            line,
+                            include_level: file.include_level,
+                        };
+                        let value = value(&context);
+                        let tokens = lexer::tokenize(value.as_str())?;
+                        return Ok(Some(tokens.into_iter().map(Token::into_owned).collect()));
+                    }
+                };
+                Ok(Some(vec![token]))
+            }),
+            locate: Box::new(move |span| file.location(span)),
+        }
+    }
 }
 
-fn format_tokens_to_string(
-    dest_str: &mut String,
-    tokens: &[Token],
-    max_depth: i32,
-    defines: &HashMap<String, Option<String>>,
-) -> Result<()> {
+pub fn process<F>(code: &str, defines: &[Define], file_loader: F) -> Result<ProcessOutput>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    Processor::new().process(code, defines, file_loader)
+}
 
-    if max_depth < 0 {
-        Err(Error::MaxRecursionDepthReached)?
+/// Finds the macro guarding a file from being included more than once, when all of the file is
+/// inside `#ifndef GUARD` or `#if !defined(GUARD)`. Including the file again has no effect while
+/// the macro is defined.
+fn include_guard<'a>(items: &'a [Item]) -> Option<&'a str> {
+    let is_blank = |item: &Item| match *item {
+        Item::Text { ref tokens, .. } => {
+            let mut tokens = tokens.iter();
+            tokens.all(|(token, _)| matches!(*token, Token::Whitespace | Token::Newline { .. }))
+        }
+        _ => false,
+    };
+    let mut items = items.iter().filter(|item| !is_blank(item));
+    let guard = match (items.next(), items.next()) {
+        (Some(Item::Conditional { branches, else_branch, .. }), None)
+            if branches.len() == 1 && else_branch.is_empty() => {
+            &branches[0].0
+        }
+        _ => return None,
+    };
+    match *guard {
+        Condition::NotDefined(ref name) => Some(name),
+        Condition::Expression { ref tokens, .. } => {
+            let words = tokens
+                .iter()
+                .map(|(token, _)| token)
+                .filter(|token| !matches!(**token, Token::Char('(') | Token::Char(')')))
+                .collect::<Vec<_>>();
+            match words[..] {
+                [Token::Char('!'), Token::Word(defined), Token::Word(name)]
+                    if defined == "defined" && tokens.len() <= 5 => Some(name),
+                _ => None,
+            }
+        }
+        _ => None,
     }
+}
 
+/// Replaces `defined NAME` and `defined ( NAME )` with `1` or `0`, and `__has_include`,
+/// `__has_include_next` and `__has_embed` with the value given by `has_operator`. This has to be
+/// done before the expression is macro expanded, so that the operand itself doesn't get expanded.
+fn replace_defined<'a, F, H>(
+    tokens: &[(Token<'a>, Span)],
+    is_defined: F,
+    has_operator: H,
+) -> Result<Vec<(Token<'a>, Span)>>
+where
+    F: Fn(&str) -> bool,
+    H: Fn(&str, &[(Token<'a>, Span)]) -> Result<usize>,
+{
+    let mut result = Vec::with_capacity(tokens.len());
     let mut i = tokens.iter();
+    let mut next = || i.next().map(|(token, span)| (token, *span));
+    while let Some((token, span)) = next() {
+        match *token {
+            Token::Word(ref word) if word == "defined" => {
+                let name = match next() {
+                    Some((Token::Word(name), _)) => name,
+                    Some((&Token::Char('('), _)) => {
+                        let name = match next() {
+                            Some((Token::Word(name), _)) => name,
+                            Some((token, _)) => Err(unexpected_token(token))?,
+                            None => Err(ExpressionError::UnexpectedEnd)?,
+                        };
+                        match next() {
+                            Some((&Token::Char(')'), _)) => name,
+                            Some((token, _)) => Err(unexpected_token(token))?,
+                            None => Err(ExpressionError::UnexpectedEnd)?,
+                        }
+                    }
+                    Some((token, _)) => Err(unexpected_token(token))?,
+                    None => Err(ExpressionError::UnexpectedEnd)?,
+                };
+                let value = if is_defined(name) {
+                    "1"
+                } else {
+                    "0"
+                };
+                result.push((Token::Word(Cow::Borrowed(value)), span));
+            }
+            Token::Word(ref word)
+                if word == "__has_include" || word == "__has_include_next" ||
+                    word == "__has_embed" =>
+            {
+                match next() {
+                    Some((&Token::Char('('), _)) => (),
+                    Some((token, _)) => Err(unexpected_token(token))?,
+                    None => Err(ExpressionError::UnexpectedEnd)?,
+                }
+                let mut operand = vec![];
+                let mut depth = 0;
+                loop {
+                    match next() {
+                        Some((&Token::Char(')'), _)) if depth == 0 => break,
+                        Some((token, token_span)) => {
+                            match *token {
+                                Token::Char('(') => depth += 1,
+                                Token::Char(')') => depth -= 1,
+                                _ => (),
+                            }
+                            operand.push((token.clone(), token_span));
+                        }
+                        None => Err(ExpressionError::UnexpectedEnd)?,
+                    }
+                }
+                result.push((number_literal(has_operator(word, &operand[..])?), span));
+            }
+            _ => result.push((token.clone(), span)),
+        }
+    }
+    Ok(result)
+}
 
-    // push first token
-    let mut token_prev = if let Some(t) = i.next() {
-        push_word_to_string(dest_str, t.formatted_str().deref(), max_depth, defines)?;
-        t
-    } else {
-        return Ok(());
+/// Parses the macro expanded arguments of `#line`: a line number and an optional file name.
+fn parse_line_arguments(tokens: &[Token]) -> Result<(usize, Option<String>)> {
+    let mut i = tokens.iter().filter(|token| **token != Token::Whitespace);
+    let number = match i.next() {
+        Some(Token::Word(number)) if number.bytes().all(|b| b.is_ascii_digit()) => {
+            number.parse().map_err(|_| ParseError::InvalidLineDirective)?
+        }
+        _ => Err(ParseError::InvalidLineDirective)?,
+    };
+    let name = match i.next() {
+        Some(Token::String(name)) => Some(unescape(name)),
+        None => None,
+        _ => Err(ParseError::InvalidLineDirective)?,
     };
+    if i.next().is_some() {
+        Err(ParseError::InvalidLineDirective)?
+    }
+    Ok((number, name))
+}
+
+/// Removes the backslashes escaping characters in the contents of a string literal.
+fn unescape(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => result.extend(chars.next()),
+            c => result.push(c),
+        }
+    }
+    result
+}
 
-    // push rest
-    for token in i {
-        if let &Token::Newline { with_escape: false } = token {
-        } else if let &Token::Newline { .. } = token_prev {
-        } else {
-            dest_str.push_str(" ");
+/// Parses the `("NAME")` argument of `push_macro` and `pop_macro`.
+fn pragma_macro_name(pragma: &str, tokens: &[Token]) -> Result<String> {
+    let mut i = tokens.iter().filter(|token| **token != Token::Whitespace);
+    match (i.next(), i.next(), i.next(), i.next()) {
+        (Some(&Token::Char('(')), Some(Token::String(name)), Some(&Token::Char(')')), None) => {
+            Ok(name.to_string())
         }
+        _ => Err(ParseError::InvalidPragmaArguments(pragma.to_string()))?,
+    }
+}
 
-        let text = token.formatted_str();
-        push_word_to_string(dest_str, text.deref(), max_depth, defines)?;
+/// Interprets the macro expanded tokens of `#include` as a `"file"` or `<file>` header name.
+fn parse_computed_header_name(tokens: &[Token]) -> Result<HeaderName<'static>> {
+    let is_whitespace = |token: &&Token| **token == Token::Whitespace;
+    let start = tokens.iter().take_while(is_whitespace).count();
+    let end = tokens.len() - tokens.iter().rev().take_while(is_whitespace).count();
+    match tokens[start..end.max(start)] {
+        [Token::String(ref name)] => Ok(HeaderName::Quoted(Cow::Owned(name.to_string()))),
+        [Token::Char('<'), ref name @ .., Token::Char('>')] => {
+            Ok(HeaderName::System(Cow::Owned(join_tokens(name))))
+        }
+        _ => Err(ParseError::InvalidHeaderName)?,
+    }
+}
 
-        token_prev = token;
+/// Splits the `"file"` or `<file>` header name from the start of the tokens.
+fn split_header_name<'t, 'a>(
+    tokens: &'t [(Token<'a>, Span)],
+) -> Option<(HeaderName<'static>, &'t [(Token<'a>, Span)])> {
+    let start = tokens.iter().position(|(token, _)| *token != Token::Whitespace)?;
+    let tokens = &tokens[start..];
+    match tokens[0].0 {
+        Token::String(ref name) => {
+            Some((HeaderName::Quoted(Cow::Owned(name.to_string())), &tokens[1..]))
+        }
+        Token::Char('<') => {
+            let end = tokens.iter().position(|(token, _)| *token == Token::Char('>'))?;
+            let name = join_tokens(&strip_spans(&tokens[1..end]));
+            Some((HeaderName::System(Cow::Owned(name)), &tokens[end + 1..]))
+        }
+        _ => None,
     }
+}
 
-    Ok(())
+/// Formats the embedded bytes as a comma-separated list of integers, between the prefix and the
+/// suffix. An empty resource is replaced with `if_empty`.
+fn format_embed(bytes: &[u8], parameters: EmbedParameters) -> String {
+    if bytes.is_empty() {
+        return parameters.if_empty.unwrap_or_default();
+    }
+    let values = bytes.iter().map(|byte| byte.to_string()).collect::<Vec<_>>();
+    [parameters.prefix, values.join(", "), parameters.suffix]
+        .iter()
+        .filter(|part| !part.is_empty())
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join(" ")
 }
 
-fn push_word_to_string(
-    dest_str: &mut String,
-    word: &str,
-    recursion_depth_left: i32,
-    defines: &HashMap<String, Option<String>>,
-) -> Result<()> {
-    if defines.contains_key(word) {
-        let mut out = String::new();
-        let value = match defines.get(word).unwrap() {
-            &None => "",
-            &Some(ref w) => w.as_str(),
-        };
-        let tokens = lexer::tokenize(value)?;
-        format_tokens_to_string(&mut out, &tokens[..], recursion_depth_left - 1, defines)?;
-        dest_str.push_str(out.as_str());
+/// Joins a file name to a directory, unless the directory is empty, and normalizes the result.
+fn join_path(directory: &str, name: &str) -> String {
+    if directory.is_empty() {
+        normalize_path(name)
     } else {
-        dest_str.push_str(word);
+        normalize_path(format!("{}/{}", directory, name).as_str())
+    }
+}
+
+/// Removes the `.` segments and empty segments from a path, and the segments followed by `..`.
+/// The `..` segments at the beginning of a relative path are kept.
+fn normalize_path(path: &str) -> String {
+    let mut segments: Vec<&str> = vec![];
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." if segments.last().is_some_and(|last| *last != "..") => {
+                segments.pop();
+            }
+            ".." if path.starts_with('/') => {}
+            segment => segments.push(segment),
+        }
+    }
+    let path_start = if path.starts_with('/') { "/" } else { "" };
+    format!("{}{}", path_start, segments.join("/"))
+}
+
+/// Drops the positions of the tokens.
+fn strip_spans<'a>(tokens: &[(Token<'a>, Span)]) -> Vec<Token<'a>> {
+    tokens.iter().map(|(token, _)| token.clone()).collect()
+}
+
+/// Spells out the tokens as they are.
+fn join_tokens(tokens: &[Token]) -> String {
+    tokens.iter().map(|token| token.formatted_str()).collect()
+}
+
+fn number_literal(value: usize) -> Token<'static> {
+    Token::Word(Cow::Owned(value.to_string()))
+}
+
+/// Formats `__DATE__` as `Mmm dd yyyy` and `__TIME__` as `hh:mm:ss`, in UTC.
+fn format_date_time(time: SystemTime) -> (String, String) {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs());
+    let (days, seconds) = (seconds / 86_400, seconds % 86_400);
+
+    // Converts the days since 1970-01-01 to a civil date, with years starting from March.
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let date = format!("{} {:2} {}", MONTHS[month as usize - 1], day, year);
+    let time = format!("{:02}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60);
+    (date, time)
+}
+
+/// Creates a string literal token, escaping quotes and backslashes.
+fn string_literal(value: &str) -> Token<'static> {
+    Token::String(Cow::Owned(value.replace('\\', "\\\\").replace('"', "\\\"")))
+}
+
+fn unexpected_token(token: &Token) -> ExpressionError {
+    ExpressionError::UnexpectedToken(token.formatted_str().into_owned())
+}
+
+fn format_tokens_to_string(dest_str: &mut String, tokens: &[Token]) {
+    let mut token_prev: Option<&Token> = None;
+    for token in tokens.iter().filter(|token| **token != Token::Whitespace) {
+        match (token_prev, token) {
+            (None, _) |
+            (_, &Token::Newline { with_escape: false }) |
+            (Some(&Token::Newline { .. }), _) => {}
+            _ => dest_str.push(' '),
+        }
+        dest_str.push_str(token.formatted_str().deref());
+        token_prev = Some(token);
     }
-    Ok(())
 }
 
 #[test]
@@ -158,23 +1248,868 @@ bar
 #endif";
 
     assert_eq!(
-        process(code, &[Define::new("TEST", "")], |_| None),
+        process(code, &[Define::new("TEST", "")], |_| None).map(|output| output.text),
+        Ok("foo\n".to_string())
+    );
+    assert_eq!(
+        process(code, &[Define::flag("TEST")], |_| None).map(|output| output.text),
         Ok("foo\n".to_string())
     );
-    assert_eq!(process(code, &[], |_| None), Ok("bar\n".to_string()));
+    assert_eq!(process(code, &[], |_| None).map(|output| output.text), Ok("bar\n".to_string()));
 }
 
 #[test]
-pub fn test_process_define() {
+pub fn test_process_if() {
     let code = "\
-#define foo bar
-foo";
-
-    assert_eq!(process(code, &[], |_| None), Ok("bar".to_string()));
-}
+#if QUALITY >= 2
+high
+#elif QUALITY == 1
+medium
+#else
+low
+#endif";
 
+    assert_eq!(
+        process(code, &[Define::new("QUALITY", "3")], |_| None).map(|output| output.text),
+        Ok("high\n".to_string())
+    );
+    assert_eq!(
+        process(code, &[Define::new("QUALITY", "1")], |_| None).map(|output| output.text),
+        Ok("medium\n".to_string())
+    );
+    assert_eq!(process(code, &[], |_| None).map(|output| output.text), Ok("low\n".to_string()));
 
-#[test]
+    let code = "\
+#define SHIFT 1 << 2
+#if (SHIFT | 0x1) == 5 && UNKNOWN == 0
+foo
+#endif";
+    assert_eq!(process(code, &[], |_| None).map(|output| output.text), Ok("foo\n".to_string()));
+
+    assert_eq!(
+        process("#if 1 / ZERO\nfoo\n#endif", &[], |_| None).map(|output| output.text)
+            .map_err(|err| err.kind),
+        Err(ErrorKind::ExpressionError(ExpressionError::DivisionByZero))
+    );
+    assert_eq!(
+        process("#if 1 +\nfoo\n#endif", &[], |_| None).map(|output| output.text)
+            .map_err(|err| err.kind),
+        Err(ErrorKind::ExpressionError(ExpressionError::UnexpectedEnd))
+    );
+}
+
+#[test]
+pub fn test_process_if_defined() {
+    let code = "\
+#if defined(USE_SHADOWS) && !defined MOBILE
+shadows
+#elif defined ( MOBILE )
+mobile
+#endif";
+
+    assert_eq!(
+        process(code, &[Define::new("USE_SHADOWS", "")], |_| None).map(|output| output.text),
+        Ok("shadows\n".to_string())
+    );
+    assert_eq!(
+        process(code, &[Define::new("USE_SHADOWS", ""), Define::new("MOBILE", "")], |_| None)
+            .map(|output| output.text),
+        Ok("mobile\n".to_string())
+    );
+    assert_eq!(process(code, &[], |_| None).map(|output| output.text), Ok("".to_string()));
+
+    // The operand of `defined` is not macro expanded.
+    let code = "\
+#define ALIAS TARGET
+#if defined(ALIAS) && !defined(TARGET)
+foo
+#endif";
+    assert_eq!(process(code, &[], |_| None).map(|output| output.text), Ok("foo\n".to_string()));
+
+    assert_eq!(
+        process("#if defined(FOO\nfoo\n#endif", &[], |_| None).map(|output| output.text)
+            .map_err(|err| err.kind),
+        Err(ErrorKind::ExpressionError(ExpressionError::UnexpectedEnd))
+    );
+}
+
+#[test]
+pub fn test_process_define() {
+    let code = "\
+#define foo bar
+foo";
+
+    assert_eq!(process(code, &[], |_| None).map(|output| output.text), Ok("bar".to_string()));
+
+    // Only the definitions in the groups which are taken are checked.
+    let code = "#if 0\n#define F(x) #y\n#endif\nok";
+    assert_eq!(process(code, &[], |_| None).map(|output| output.text), Ok("ok".to_string()));
+    assert_eq!(
+        process("foo\n#define F(x) #y", &[], |_| None),
+        Err(Error::from(ParseError::StringizeWithoutParameter).or_at(Location {
+            file: "<input>".to_string(),
+            line: 2,
+            column: 1,
+        }))
+    );
+}
+
+
+#[test]
+pub fn test_process_function_macro() {
+    let code = "\
+#define SATURATE(x) clamp(x, 0.0, 1.0)
+#define MUL(a, b) a * b
+#define TWO 2.0
+SATURATE(MUL(TWO,
+             value))";
+
+    assert_eq!(
+        process(code, &[], |_| None).map(|output| output.text),
+        Ok("clamp ( 2.0 * value , 0.0 , 1.0 )".to_string())
+    );
+
+    let code = "\
+#define SQUARE(x) ((x) * (x))
+#if SQUARE(3) == 9
+foo
+#endif";
+    assert_eq!(process(code, &[], |_| None).map(|output| output.text), Ok("foo\n".to_string()));
+}
+
+#[test]
+pub fn test_process_stringize_and_paste() {
+    let code = "\
+#define UNIFORM(n) u_##n
+#define LABEL(x) #x
+uniform vec4 UNIFORM(albedo); // LABEL(albedo)
+debug(LABEL(albedo));";
+
+    assert_eq!(
+        process(code, &[], |_| None).map(|output| output.text),
+        Ok("uniform vec4 u_albedo ;\ndebug ( \"albedo\" ) ;".to_string())
+    );
+}
+
+#[test]
+pub fn test_process_variadic_macro() {
+    let code = "\
+#define LOG(fmt, ...) printf(fmt, ## __VA_ARGS__)
+LOG(\"a\")
+LOG(\"b\", 1, 2)";
+
+    assert_eq!(
+        Processor::new()
+            .gnu_comma_paste(true)
+            .process(code, &[], |_| None)
+            .map(|output| output.text),
+        Ok("printf ( \"a\" )\nprintf ( \"b\" , 1 , 2 )".to_string())
+    );
+    assert_eq!(
+        process(code, &[], |_| None).map(|output| output.text).map_err(|err| err.kind),
+        Err(ErrorKind::InvalidTokenPaste(",1".to_string()))
+    );
+
+    let code = "\
+#define LOG(fmt, ...) printf(fmt __VA_OPT__(,) __VA_ARGS__)
+LOG(\"a\")
+LOG(\"b\", 1)";
+    assert_eq!(
+        process(code, &[], |_| None).map(|output| output.text),
+        Ok("printf ( \"a\" )\nprintf ( \"b\" , 1 )".to_string())
+    );
+}
+
+#[test]
+pub fn test_process_diagnostics() {
+    let code = "\
+#include \"lights\"
+#if LIGHTS > 4
+#error \"Unsupported light count\"
+#endif
+foo";
+    let lights = "\
+#warning Lights are deprecated
+#define LIGHTS 8";
+
+    assert_eq!(
+        process(code, &[], |_| Some(lights.to_string())),
+        Err(Error::from(ErrorKind::ErrorDirective("\"Unsupported light count\"".to_string()))
+            .or_at(Location {
+                file: "<input>".to_string(),
+                line: 3,
+                column: 1,
+            }))
+    );
+    assert_eq!(
+        process("#include \"lights\"\n#undef LIGHTS\n", &[], |_| Some(lights.to_string())),
+        Ok(ProcessOutput {
+            text: "\n\n".to_string(),
+            warnings: vec![
+                Warning {
+                    message: "Lights are deprecated".to_string(),
+                    location: Location {
+                        file: "lights".to_string(),
+                        line: 1,
+                        column: 1,
+                    },
+                },
+            ],
+            errors: vec![],
+        })
+    );
+}
+
+#[test]
+pub fn test_process_error_location() {
+    let loader = |name: &str| match name {
+        "lights" => Some("#define LIGHTS\n  #if LIGHTS > 4\n#endif\n".to_string()),
+        "broken" => Some("\n#include\n".to_string()),
+        _ => None,
+    };
+    let location = |file: &str, line, column| Some(Location {
+        file: file.to_string(),
+        line,
+        column,
+    });
+
+    assert_eq!(
+        process("#include \"lights\"\n", &[], loader).map_err(|err| err.context.location),
+        Err(location("lights", 2, 3))
+    );
+    assert_eq!(
+        process("\n#include \"broken\"\n", &[], loader).map_err(|err| err.context.location),
+        Err(location("broken", 2, 9))
+    );
+    assert_eq!(
+        process("foo\n#include \"missing\"\n", &[], loader).map_err(|err| err.context.location),
+        Err(location("<input>", 2, 1))
+    );
+
+    // Macro invocations are located at the name of the macro, after a `#line` as well.
+    let code = "#define SHADE(a, b) a * b\nline1\nline2\nline3\nvec3 c = SHADE(color);";
+    assert_eq!(
+        process(code, &[], loader).map_err(|err| err.context.location),
+        Err(location("<input>", 5, 10))
+    );
+    let code = "#define F(x) x\n#define G(x, y) x\n#line 20\nF(\n  G(1)\n)\n";
+    assert_eq!(
+        process(code, &[], loader).map_err(|err| err.context.location),
+        Err(location("<input>", 21, 3))
+    );
+}
+
+#[test]
+pub fn test_process_recovering() {
+    let loader = |name: &str| match name {
+        "lights.glsl" => Some("#if LIGHTS >\n#endif\nlight\n#frobnicate\n".to_string()),
+        _ => None,
+    };
+    let code = "\
+#ifdef SHADOWS
+#include \"lights.glsl\"
+#include \"missing.glsl\"
+#error No shadows
+foo
+";
+    let processor = Processor::new().recover_errors(true);
+    let output = processor.process(code, &[Define::flag("SHADOWS")], loader).unwrap();
+    assert_eq!(output.text, "light\n\n\nfoo\n");
+    let location = |file: &str, line| Location {
+        file: file.to_string(),
+        line,
+        column: 1,
+    };
+    assert_eq!(
+        output
+            .errors
+            .into_iter()
+            .map(|err| (err.kind, err.context.location, err.context.include_stack))
+            .collect::<Vec<_>>(),
+        vec![
+            (
+                ErrorKind::ParsingError(ParseError::IfWithoutEndif),
+                Some(location("<input>", 1)),
+                vec![],
+            ),
+            (
+                ErrorKind::ParsingError(ParseError::UnrecognizedPreprocessor(
+                    "frobnicate".to_string(),
+                )),
+                Some(location("lights.glsl", 4)),
+                vec![location("<input>", 2)],
+            ),
+            (
+                ErrorKind::ExpressionError(ExpressionError::UnexpectedEnd),
+                Some(location("lights.glsl", 1)),
+                vec![location("<input>", 2)],
+            ),
+            (ErrorKind::CantOpenFile, Some(location("<input>", 3)), vec![]),
+            (
+                ErrorKind::ErrorDirective("No shadows".to_string()),
+                Some(location("<input>", 4)),
+                vec![],
+            ),
+        ]
+    );
+
+    assert_eq!(
+        process(code, &[Define::flag("SHADOWS")], loader).map_err(|err| err.kind),
+        Err(ErrorKind::ParsingError(ParseError::IfWithoutEndif))
+    );
+}
+
+#[test]
+pub fn test_process_invalid_utf8() {
+    struct Loader;
+    impl FileLoader for Loader {
+        fn load(&self, _: &str) -> Option<String> {
+            None
+        }
+        fn load_bytes(&self, name: &str) -> Option<Vec<u8>> {
+            match name {
+                "latin1.glsl" => Some(b"// Caf\xe9\nfoo\n".to_vec()),
+                _ => None,
+            }
+        }
+        fn exists(&self, name: &str) -> bool {
+            self.load_bytes(name).is_some()
+        }
+    }
+    let code = "#include \"latin1.glsl\"\n";
+
+    assert_eq!(
+        Processor::new().process_with_loader(code, &[], &Loader),
+        Err(Error::from(ErrorKind::LexingError(LexingError::InvalidUtf8))
+            .or_at(Location {
+                file: "latin1.glsl".to_string(),
+                line: 1,
+                column: 7,
+            })
+            .included_from(Location {
+                file: "<input>".to_string(),
+                line: 1,
+                column: 1,
+            }))
+    );
+    let output = Processor::new()
+        .recover_errors(true)
+        .process_with_loader(code, &[], &Loader)
+        .unwrap();
+    assert_eq!(output.text, "\nfoo\n\n");
+    assert_eq!(output.errors.len(), 1);
+}
+
+#[test]
+pub fn test_process_include_stack() {
+    let loader = |name: &str| match name {
+        "a.glsl" => Some("\n\n#include \"lights.glsl\"\n".to_string()),
+        "lights.glsl" => Some("#if LIGHTS > 4\n#endif\n#error Too many lights\n".to_string()),
+        "broken.glsl" => Some("#if 1\n".to_string()),
+        _ => None,
+    };
+    let location = |file: &str, line| Location {
+        file: file.to_string(),
+        line,
+        column: 1,
+    };
+    let code = "\
+#define LIGHTS 8
+#include \"a.glsl\"
+";
+
+    let err = process(code, &[], loader).unwrap_err();
+    assert_eq!(err.context.location, Some(location("lights.glsl", 3)));
+    assert_eq!(err.context.include_stack, vec![location("a.glsl", 3), location("<input>", 2)]);
+    assert_eq!(
+        err.to_string(),
+        "in file included from a.glsl:3, from <input>:2:\nlights.glsl:3:1: #error Too many lights"
+    );
+    assert_eq!(
+        process("#include \"broken.glsl\"\n", &[], loader)
+            .map_err(|err| err.context.include_stack),
+        Err(vec![location("<input>", 1)])
+    );
+    assert_eq!(
+        process("#include \"missing.glsl\"\n", &[], loader)
+            .map_err(|err| err.context.include_stack),
+        Err(vec![])
+    );
+}
+
+#[test]
+pub fn test_process_line() {
+    let code = "\
+#include \"header\"
+__FILE__:__LINE__
+#line 100 \"template.glsl\"
+__FILE__:__LINE__
+#define BASE 200
+#line BASE
+#if __LINE__ == 200 && defined(__FILE__)
+__LINE__
+#endif";
+    let header = "__FILE__:__LINE__\n\n__LINE__\n";
+
+    assert_eq!(
+        process(code, &[], |_| Some(header.to_string())).map(|output| output.text),
+        Ok("\"header\" : 1\n\n3\n\n\"<input>\" : 2\n\"template.glsl\" : 100\n201\n".to_string())
+    );
+
+    assert_eq!(
+        process("#line 7 \"a\\\\b.h\"\n__FILE__\n#error failed", &[], |_| None),
+        Err(Error::from(ErrorKind::ErrorDirective("failed".to_string())).or_at(Location {
+            file: "a\\b.h".to_string(),
+            line: 8,
+            column: 1,
+        }))
+    );
+    assert_eq!(
+        process("#line 10 \\\n\"a\"\n__FILE__:__LINE__\n", &[], |_| None)
+            .map(|output| output.text),
+        Ok("\"a\" : 10\n".to_string())
+    );
+    assert_eq!(
+        process("#line x\n", &[], |_| None).map_err(|err| err.kind),
+        Err(ErrorKind::ParsingError(ParseError::InvalidLineDirective))
+    );
+}
+
+#[test]
+pub fn test_process_builtins() {
+    let code = "\
+#include \"header\"
+__COUNTER__ __COUNTER__ __INCLUDE_LEVEL__ __BASE_FILE__
+__DATE__ __TIME__
+#if defined(__SHADER_STAGE__) && __SHADER_STAGE__ == 1
+__FILE__ __SHADER_STAGE__
+#endif";
+    let header = "__COUNTER__ __INCLUDE_LEVEL__ __BASE_FILE__ LOCATION\n";
+
+    let processor = Processor::new()
+        .date("Jan  1 2024")
+        .time("12:00:00")
+        .builtin("__SHADER_STAGE__", |_| "1".to_string())
+        .builtin("LOCATION", |context| {
+            format!("\"{}:{}\"", context.file, context.line)
+        });
+    assert_eq!(
+        processor
+            .process(code, &[], |_| Some(header.to_string()))
+            .map(|output| output.text),
+        Ok(concat!(
+            "0 1 \"<input>\" \"header:1\"\n",
+            "\n",
+            "1 2 0 \"<input>\"\n",
+            "\"Jan  1 2024\" \"12:00:00\"\n",
+            "\"<input>\" 1\n",
+        ).to_string())
+    );
+
+    let file_loader = |name: &str| match name {
+        "shaders/lights.glsl" => Some("__FILE__ __BASE_FILE__\n#error no lights".to_string()),
+        _ => None,
+    };
+    let code = "__FILE__ __BASE_FILE__\n#include \"lights.glsl\"";
+    let err = Processor::new()
+        .process_file("shaders/pbr.glsl", code, &[], &file_loader)
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "in file included from shaders/pbr.glsl:2:\nshaders/lights.glsl:2:1: #error no lights"
+    );
+    let file_loader = |name: &str| match name {
+        "shaders/lights.glsl" => Some("__FILE__ __BASE_FILE__".to_string()),
+        _ => None,
+    };
+    assert_eq!(
+        Processor::new()
+            .process_file("shaders/pbr.glsl", code, &[], &file_loader)
+            .map(|output| output.text),
+        Ok(concat!(
+            "\"shaders/pbr.glsl\" \"shaders/pbr.glsl\"\n",
+            "\"shaders/lights.glsl\" \"shaders/pbr.glsl\"",
+        ).to_string())
+    );
+}
+
+#[test]
+pub fn test_format_date_time() {
+    use std::time::Duration;
+
+    let time = |seconds| UNIX_EPOCH + Duration::from_secs(seconds);
+    assert_eq!(
+        format_date_time(time(0)),
+        ("Jan  1 1970".to_string(), "00:00:00".to_string())
+    );
+    assert_eq!(
+        format_date_time(time(951_825_599)),
+        ("Feb 29 2000".to_string(), "11:59:59".to_string())
+    );
+    assert_eq!(
+        format_date_time(time(1_704_067_199)),
+        ("Dec 31 2023".to_string(), "23:59:59".to_string())
+    );
+}
+
+#[test]
+pub fn test_process_include_search_paths() {
+    let code = "\
+#include \"a.h\"
+#include <a.h>
+#include \"b.h\"
+#include <c.h>
+#include </abs/c.h>";
+    let file_loader = |name: &str| match name {
+        "a.h" | "quote/a.h" | "include/a.h" | "include/b.h" | "system/b.h" | "system/c.h" |
+        "/abs/c.h" => Some(name.replace('/', "_")),
+        _ => None,
+    };
+    let processor = Processor::new()
+        .quote_path("quote")
+        .include_path("include/")
+        .system_path("system");
+
+    assert_eq!(
+        processor.process(code, &[], file_loader).map(|output| output.text),
+        Ok("a.h\ninclude_a.h\ninclude_b.h\nsystem_c.h\n_abs_c.h".to_string())
+    );
+    assert_eq!(
+        processor.process("#include <d.h>", &[], file_loader).map_err(|err| err.kind),
+        Err(ErrorKind::CantOpenFile)
+    );
+    assert_eq!(
+        Processor::new().process("#include <a.h>", &[], file_loader).map(|output| output.text),
+        Ok("a.h".to_string())
+    );
+}
+
+#[test]
+pub fn test_process_has_include() {
+    let code = "\
+#if __has_include(\"extra.h\") && !__has_include(<missing.h>)
+extra
+#endif
+#define HEADER <base.h>
+#if defined __has_include && __has_include(HEADER)
+base
+#endif
+#include <base.h>";
+    let file_loader = |name: &str| match name {
+        "mod/extra.h" | "engine/base.h" => Some("included".to_string()),
+        "mod/base.h" => Some("#if __has_include_next(<base.h>)\nnext\n#endif".to_string()),
+        _ => None,
+    };
+    let processor = Processor::new().include_path("mod").include_path("engine");
+
+    assert_eq!(
+        processor.process(code, &[], file_loader).map(|output| output.text),
+        Ok("extra\nbase\nnext\n".to_string())
+    );
+    assert_eq!(
+        processor.process("#if __has_include(\"extra.h\"\n#endif", &[], file_loader)
+            .map_err(|err| err.kind),
+        Err(ErrorKind::ExpressionError(ExpressionError::UnexpectedEnd))
+    );
+}
+
+#[test]
+pub fn test_process_include_next() {
+    let file_loader = |name: &str| match name {
+        "mod/common.h" => Some("mod\n#include_next \"common.h\"".to_string()),
+        "engine/common.h" => Some("engine".to_string()),
+        "engine/last.h" => Some("#include_next <last.h>".to_string()),
+        _ => None,
+    };
+    let processor = Processor::new()
+        .include_path("mod")
+        .include_path("patch")
+        .include_path("engine");
+
+    assert_eq!(
+        processor.process("#include <common.h>", &[], file_loader).map(|output| output.text),
+        Ok("mod\nengine".to_string())
+    );
+    assert_eq!(
+        processor.process("#include_next <common.h>", &[], file_loader).map(|output| output.text),
+        Ok("mod\nengine".to_string())
+    );
+    assert_eq!(
+        processor.process("#include <last.h>", &[], file_loader).map_err(|err| err.kind),
+        Err(ErrorKind::CantOpenFile)
+    );
+
+    let file_loader = |name: &str| match name {
+        "dir/a.h" => Some("a\n#include_next \"a.h\"".to_string()),
+        "inc/a.h" => Some("base".to_string()),
+        _ => None,
+    };
+    let processor = Processor::new().include_path("inc");
+    assert_eq!(
+        processor.process("#include \"dir/a.h\"", &[], file_loader).map(|output| output.text),
+        Ok("a\nbase".to_string())
+    );
+    assert_eq!(
+        Processor::new().process("#include \"dir/a.h\"", &[], file_loader).map_err(|err| err.kind),
+        Err(ErrorKind::CantOpenFile)
+    );
+}
+
+#[test]
+pub fn test_process_include_depth() {
+    // Unoptimized code needs more stack for the nested includes than test threads have.
+    let thread = ::std::thread::Builder::new().stack_size(16 << 20).spawn(|| {
+        let file_loader = |name: &str| match name {
+            "self.h" => Some("#include \"self.h\"".to_string()),
+            _ => None,
+        };
+        process("#include \"self.h\"", &[], file_loader).unwrap_err()
+    });
+    let err = thread.unwrap().join().unwrap();
+    assert_eq!(err.kind, ErrorKind::IncludeTooDeep(MAX_INCLUDE_DEPTH));
+    assert_eq!(err.context.include_stack.len(), MAX_INCLUDE_DEPTH);
+}
+
+#[test]
+pub fn test_process_embed() {
+    let code = "\
+#define LUT_SIZE 2
+#embed \"lut.bin\" limit(LUT_SIZE + 1) prefix(0, ) suffix(, 0)
+#embed <empty.bin> if_empty(none)
+#define EMPTY <empty.bin>
+#if __has_embed(\"lut.bin\") == 1 && __has_embed(EMPTY) == 2 && !__has_embed(\"no.bin\")
+found
+#endif
+#if __has_embed(\"lut.bin\" __limit__(0)) == 2 && !__has_embed(\"lut.bin\" gnu(0))
+limited
+#endif";
+    let file_loader = |name: &str| match name {
+        "lut.bin" => Some("ABCD".to_string()),
+        "empty.bin" => Some(String::new()),
+        _ => None,
+    };
+
+    assert_eq!(
+        process(code, &[], file_loader).map(|output| output.text),
+        Ok("0 , 65, 66, 67 , 0\nnone\nfound\nlimited\n".to_string())
+    );
+    assert_eq!(
+        process("#embed \"lut.bin\" offset(1)", &[], file_loader).map_err(|err| err.kind),
+        Err(ErrorKind::ParsingError(ParseError::InvalidEmbedParameter("offset".to_string())))
+    );
+    assert_eq!(
+        process("#embed \"no.bin\"", &[], file_loader).map_err(|err| err.kind),
+        Err(ErrorKind::CantOpenFile)
+    );
+}
+
+#[test]
+pub fn test_process_computed_include() {
+    let code = "\
+#define PLATFORM_HEADER \"platform_gl.h\"
+#define SYSTEM_HEADER(dir) <dir/types.h>
+#include PLATFORM_HEADER
+#include SYSTEM_HEADER(sys)";
+    let file_loader = |name: &str| match name {
+        "platform_gl.h" => Some("gl".to_string()),
+        "sys/types.h" => Some("types".to_string()),
+        _ => None,
+    };
+
+    assert_eq!(
+        process(code, &[], file_loader).map(|output| output.text),
+        Ok("gl\ntypes".to_string())
+    );
+    assert_eq!(
+        process("#define EMPTY\n#include EMPTY", &[], file_loader).map_err(|err| err.kind),
+        Err(ErrorKind::ParsingError(ParseError::InvalidHeaderName))
+    );
+    assert_eq!(
+        process("#include PLATFORM_HEADER \"a\"", &[], file_loader).map_err(|err| err.kind),
+        Err(ErrorKind::ParsingError(ParseError::InvalidHeaderName))
+    );
+}
+
+#[test]
+pub fn test_process_nested_include() {
+    let file_loader = |name: &str| match name {
+        "lighting/brdf.glsl" => {
+            Some("#include \"ggx.glsl\"\n#include \"../common.glsl\"".to_string())
+        }
+        "lighting/ggx.glsl" => {
+            Some("#include \"./../lighting//.//fresnel.glsl\"\nggx".to_string())
+        }
+        "lighting/fresnel.glsl" => Some("fresnel".to_string()),
+        "common.glsl" => Some("common".to_string()),
+        _ => None,
+    };
+
+    assert_eq!(
+        process("#include \"lighting/brdf.glsl\"", &[], file_loader).map(|output| output.text),
+        Ok("fresnel\nggx\ncommon".to_string())
+    );
+}
+
+#[test]
+pub fn test_normalize_path() {
+    assert_eq!(normalize_path("a/./b//c/../d"), "a/b/d");
+    assert_eq!(normalize_path("../a/../../b"), "../../b");
+    assert_eq!(normalize_path("/../a/./b/.."), "/a");
+    assert_eq!(normalize_path("a/.."), "");
+}
+
+#[test]
+pub fn test_process_include_once() {
+    use std::cell::RefCell;
+
+    let code = "\
+#include \"once\"
+#include \"guarded\"
+#include \"alias\"
+#include \"guarded\"
+#include \"unguarded\"
+#include \"unguarded\"";
+    let loads = RefCell::new(vec![]);
+    let file_loader = |name: &str| {
+        loads.borrow_mut().push(name.to_string());
+        match name {
+            "once" | "alias" => Some("#pragma once\nonce".to_string()),
+            "guarded" => {
+                Some("// guard\n#if !defined(GUARD)\n#define GUARD\nguarded\n#endif".to_string())
+            }
+            "unguarded" => Some("#ifndef X\nunguarded\n#endif\nafter".to_string()),
+            _ => None,
+        }
+    };
+
+    struct Loader<F>(F);
+    impl<F: Fn(&str) -> Option<String>> FileLoader for Loader<F> {
+        fn load(&self, name: &str) -> Option<String> {
+            (self.0)(name)
+        }
+        fn canonical_id(&self, name: &str) -> String {
+            if name == "alias" { "once" } else { name }.to_string()
+        }
+    }
+
+    assert_eq!(
+        Processor::new()
+            .process_with_loader(code, &[], &Loader(&file_loader))
+            .map(|output| output.text),
+        Ok("once\n\nguarded\n\n\n\nunguarded\nafter\nunguarded\nafter".to_string())
+    );
+    assert_eq!(*loads.borrow(), vec!["once", "guarded", "unguarded", "unguarded"]);
+
+    // Without canonical ids the names are different files.
+    loads.borrow_mut().clear();
+    assert_eq!(
+        process("#include \"once\"\n#include \"alias\"", &[], file_loader)
+            .map(|output| output.text),
+        Ok("once\nonce".to_string())
+    );
+
+    // Skipping a guarded file doesn't search for it again.
+    loads.borrow_mut().clear();
+    let search_loader = |name: &str| {
+        loads.borrow_mut().push(name.to_string());
+        match name {
+            "b/g.h" => Some("#ifndef G\n#define G\ng\n#endif".to_string()),
+            _ => None,
+        }
+    };
+    assert_eq!(
+        Processor::new()
+            .include_path("a")
+            .include_path("b")
+            .process("#include <g.h>\n#include <g.h>\n#include <g.h>", &[], search_loader)
+            .map(|output| output.text),
+        Ok("g\n\n\n".to_string())
+    );
+    assert_eq!(*loads.borrow(), vec!["a/g.h", "b/g.h"]);
+}
+
+#[test]
+pub fn test_process_pragma() {
+    let code = "\
+#define TEXT \"from macro\"
+#pragma pack_matrix(row_major)
+#pragma optionNV(unroll all)
+#pragma message(\"compiling \" TEXT)
+#pragma STDC FP_CONTRACT ON
+foo";
+
+    assert_eq!(
+        process(code, &[], |_| None),
+        Ok(ProcessOutput {
+            text: "#pragma pack_matrix(row_major)\n#pragma optionNV(unroll all)\n\
+                   #pragma STDC FP_CONTRACT ON\nfoo"
+                .to_string(),
+            warnings: vec![
+                Warning {
+                    message: "compiling from macro".to_string(),
+                    location: Location {
+                        file: "<input>".to_string(),
+                        line: 4,
+                        column: 1,
+                    },
+                },
+            ],
+            errors: vec![],
+        })
+    );
+
+    let processor = Processor::new()
+        .pragma_handler("pack_matrix", |_| PragmaAction::Consume)
+        .pragma_handler("optionNV", |arguments| {
+            PragmaAction::Rewrite(format!("// optionNV {}", arguments))
+        })
+        .pragma_handler("STDC", |_| PragmaAction::Reject("STDC is not supported".to_string()));
+    assert_eq!(
+        processor.process(code, &[], |_| None),
+        Err(Error::from(ErrorKind::RejectedPragma("STDC is not supported".to_string()))
+            .or_at(Location {
+                file: "<input>".to_string(),
+                line: 5,
+                column: 1,
+            }))
+    );
+    assert_eq!(
+        processor
+            .process("#pragma pack_matrix(row_major)\n#pragma optionNV(unroll all)", &[], |_| None)
+            .map(|output| output.text),
+        Ok("// optionNV (unroll all)\n".to_string())
+    );
+}
+
+#[test]
+pub fn test_process_push_pop_macro() {
+    let code = "\
+#define PRECISION highp
+#pragma push_macro(\"PRECISION\")
+#undef PRECISION
+#define PRECISION mediump
+#pragma push_macro(\"PRECISION\")
+#pragma push_macro( \"TEMP\" )
+#define TEMP 1
+PRECISION TEMP
+#pragma pop_macro(\"TEMP\")
+#pragma pop_macro(\"PRECISION\")
+PRECISION TEMP
+#pragma pop_macro(\"PRECISION\")
+#pragma pop_macro(\"PRECISION\")
+PRECISION";
+
+    assert_eq!(
+        process(code, &[], |_| None).map(|output| output.text),
+        Ok("\nmediump 1\nmediump TEMP\nhighp".to_string())
+    );
+    assert_eq!(
+        process("#pragma push_macro(PRECISION)", &[], |_| None).map_err(|err| err.kind),
+        Err(ErrorKind::ParsingError(ParseError::InvalidPragmaArguments("push_macro".to_string())))
+    );
+}
+
+#[test]
 pub fn test_process_include() {
     let code = "\
 #include \"test\"
@@ -185,21 +2120,30 @@ bar";
         process(code, &[], |f| match f {
             "test" => Some(code_2.to_string()),
             _ => None,
-        }),
+        }).map(|output| output.text),
         Ok("foo\nbar".to_string())
     );
+
+    // A backslash in a `<file>` header name is part of the name.
+    let loader = |f: &str| match f {
+        "dir\\file.h" => Some("file".to_string()),
+        "dir\\data.bin" => Some("A".to_string()),
+        _ => None,
+    };
+    assert_eq!(
+        process("#include <dir\\file.h>\n#embed <dir\\data.bin>\n", &[], loader)
+            .map(|output| output.text),
+        Ok("file\n65\n".to_string())
+    );
 }
 
 #[test]
-pub fn test_overflow() {
+pub fn test_recursive_macro() {
 
     let code = "\
 #define foo bar
 #define bar foo
 foo";
 
-    assert_eq!(
-        process(code, &[], |_| None),
-        Err(Error::MaxRecursionDepthReached)
-    );
+    assert_eq!(process(code, &[], |_| None).map(|output| output.text), Ok("foo".to_string()));
 }